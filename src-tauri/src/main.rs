@@ -4,100 +4,812 @@
 )]
 
 use std::{
+    collections::{hash_map::DefaultHasher, HashMap, HashSet, VecDeque},
     fs,
+    hash::{Hash, Hasher},
+    io::{Cursor, Write},
     path::{Path, PathBuf},
-    sync::Mutex,
-    time::Duration,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant, SystemTime},
 };
 
-use chrono::{Datelike, Local, NaiveTime, Weekday};
-use rand::{seq::SliceRandom, thread_rng};
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, Timelike, Utc, Weekday};
+use cron::Schedule;
+use log::{debug, error, info, warn, LevelFilter};
+use notify_debouncer_mini::{
+    new_debouncer,
+    notify::{RecommendedWatcher, RecursiveMode},
+    DebounceEventResult, Debouncer,
+};
+use rand::{rngs::StdRng, seq::SliceRandom, Rng, SeedableRng};
 use serde::{Deserialize, Serialize};
 use tauri::{
+    image::Image,
     menu::{Menu, MenuItem, PredefinedMenuItem},
-    tray::{MouseButton, TrayIconBuilder, TrayIconEvent},
-    LogicalSize, Manager, RunEvent, Size, WindowEvent,
+    tray::{MouseButton, TrayIcon, TrayIconBuilder, TrayIconEvent},
+    Emitter, LogicalSize, Manager, PhysicalPosition, RunEvent, Size, WindowEvent,
 };
+use tauri_plugin_autostart::{MacosLauncher, ManagerExt};
+use tauri_plugin_clipboard_manager::ClipboardExt;
+use tauri_plugin_dialog::{DialogExt, MessageDialogButtons, MessageDialogKind};
+use tauri_plugin_global_shortcut::{GlobalShortcutExt, ShortcutState};
 use tokio::sync::Notify;
 use tokio::time::sleep;
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
+#[derive(Debug, Clone, PartialEq, Deserialize, Serialize)]
 #[serde(rename_all = "camelCase")]
 struct AppConfig {
+    // format version of the config file (see migrate_config)
+    #[serde(default = "default_config_version")]
+    version: u32,
     #[serde(default = "default_interval")]
     interval: u64,
+    // unit of `interval`: "seconds" (default when absent), "minutes" or "hours"
+    #[serde(default)]
+    interval_unit: Option<String>,
+    // each wait becomes interval ± rand(0..=jitter) seconds (never below 1s)
+    #[serde(default)]
+    interval_jitter_secs: Option<u64>,
+    // cron expression ("sec min hour day month weekday [year]"); when set it takes
+    // precedence over interval / start_dt / end_dt / weekly / monthly
+    #[serde(default)]
+    cron: Option<String>,
     #[serde(default)]
     start_dt: Option<String>,
     #[serde(default)]
     end_dt: Option<String>,
+    // "do not disturb" window ("HH:MM"): never change the wallpaper inside it, even within
+    // start_dt / end_dt (may wrap past midnight)
+    #[serde(default)]
+    dnd_start: Option<String>,
+    #[serde(default)]
+    dnd_end: Option<String>,
     #[serde(default)]
     weekly: Option<Vec<String>>,
     #[serde(default)]
     monthly: Option<Vec<u32>>,
+    // treat monthly days past the end of the month as the month's last day (31 -> Feb 28/29)
+    #[serde(default)]
+    monthly_clamp: bool,
+    // "last", "last-weekday", "last-fri", "2nd-mon", ... (OR-ed with monthly)
+    #[serde(default)]
+    monthly_patterns: Option<Vec<String>>,
+    // "error" / "warn" / "info" (default when absent) / "debug" / "trace"
+    #[serde(default)]
+    log_level: Option<String>,
+    // "json" / "toml": format written on save (absent = keep the format of the existing file)
+    #[serde(default)]
+    config_format: Option<String>,
+    // restored outside the schedule window instead of initial_wallpaper when set
     #[serde(default)]
     default_wallpaper_path: Option<PathBuf>,
+    // the user's own wallpaper from before rotation, kept on disk for crash recovery
+    #[serde(default)]
+    initial_wallpaper: Option<PathBuf>,
     #[serde(default)]
     file_targets: Vec<PathBuf>,
+    // folders rescanned while running, so images added later join the rotation
+    #[serde(default)]
+    folder_targets: Vec<PathBuf>,
+    // how many subfolder levels to descend when scanning folders (0 = the folder only,
+    // absent = unlimited)
+    #[serde(default)]
+    scan_max_depth: Option<usize>,
+    // also scan dotfiles / dot folders (and on Windows, entries with the hidden attribute)
+    #[serde(default)]
+    include_hidden: bool,
+    // upper bound on file_targets; add_file_targets randomly samples new images down to it
+    #[serde(default)]
+    max_targets: Option<usize>,
+    // named image sets; file_targets (+ folder_targets) stay the default, unnamed one
+    #[serde(default)]
+    playlists: HashMap<String, Playlist>,
+    // playlist the loop draws from (None = file_targets)
+    #[serde(default)]
+    active_playlist: Option<String>,
+    // 画像ごとのタグ（"dark" / "nature" など）
+    #[serde(default)]
+    tags: HashMap<PathBuf, Vec<String>>,
+    // 空でなければ、いずれかのタグが付いた画像だけを切り替える
+    #[serde(default)]
+    active_tags: Vec<String>,
     #[serde(default = "default_random")]
     random: bool,
-    // persisted window state (width/height in pixels and minimized flag)
+    // virtual desktop id (GUID string) -> images shown while that desktop is active (Windows only)
+    #[serde(default)]
+    virtual_desktop_targets: HashMap<String, Vec<PathBuf>>,
+    // number of shown wallpapers kept in the history log (capped at MAX_HISTORY)
+    #[serde(default = "default_history_limit")]
+    history_limit: usize,
+    // also keep the history log in history.json so it survives restarts
+    #[serde(default)]
+    persist_history: bool,
+    // fixed seed for random mode so the same targets yield the same order
+    #[serde(default)]
+    random_seed: Option<u64>,
+    // one image per calendar day, derived from the date; `interval` then only sets
+    // how often the loop polls for the date change / schedule window
+    #[serde(default)]
+    daily_mode: bool,
+    // false = manual-only: the loop never changes the wallpaper by itself
+    #[serde(default = "default_auto_rotate")]
+    auto_rotate: bool,
+    // restore the initial wallpaper when the app quits
+    #[serde(default = "default_true")]
+    restore_on_quit: bool,
+    // image set when the app quits instead of restoring the initial wallpaper
+    // (only with restore_on_quit; false keeps the current wallpaper)
+    #[serde(default)]
+    exit_wallpaper_path: Option<PathBuf>,
+    // global shortcuts (unset = not bound), e.g.
+    //   hotkey_next: "CommandOrControl+Alt+Right" -> next wallpaper
+    //   hotkey_prev: "CommandOrControl+Alt+Left"  -> previous wallpaper
+    //   hotkey_toggle_pause: "CommandOrControl+Alt+P" -> pause / resume rotation
+    #[serde(default)]
+    hotkey_next: Option<String>,
+    #[serde(default)]
+    hotkey_prev: Option<String>,
+    #[serde(default)]
+    hotkey_toggle_pause: Option<String>,
+    // rotation paused by the user (kept across restarts)
+    #[serde(default)]
+    paused: bool,
+    // restore the initial wallpaper when leaving the schedule window / running out of targets
+    #[serde(default = "default_true")]
+    restore_outside_schedule: bool,
+    // before restoring, adopt a wallpaper the user set themselves (not one of ours) as the
+    // new initial wallpaper instead of overwriting it
+    #[serde(default)]
+    respect_external_changes: bool,
+    // also set the lock screen image after the desktop wallpaper (Windows only)
+    #[serde(default)]
+    set_lockscreen: bool,
+    // solid color ("#rrggbb") shown when there is neither a target nor a wallpaper to restore
+    #[serde(default)]
+    fallback_color: Option<String>,
+    // "morning" / "afternoon" / "evening" / "night" -> images used during that part of the day
+    // (an empty or missing slot falls back to file_targets)
     #[serde(default)]
-    window_width: Option<u32>,
+    time_collections: Option<HashMap<String, Vec<PathBuf>>>,
+    // hour each time slot starts at
     #[serde(default)]
-    window_height: Option<u32>,
+    time_slot_hours: TimeSlotHours,
+    // images used while the OS is in light / dark mode (an empty list falls back to file_targets)
     #[serde(default)]
-    window_minimized: Option<bool>,
+    light_targets: Vec<PathBuf>,
+    #[serde(default)]
+    dark_targets: Vec<PathBuf>,
+    // location (degrees, east positive); when both are set, rotate only between sunrise and
+    // sunset instead of start_dt / end_dt
+    #[serde(default)]
+    latitude: Option<f64>,
+    #[serde(default)]
+    longitude: Option<f64>,
+    // read each image header before setting it and skip unreadable / corrupt files
+    #[serde(default)]
+    validate_before_set: bool,
+    // log / emit each pick instead of setting it (rotation state still advances)
+    #[serde(default)]
+    dry_run: bool,
+    // random mode avoids the last N picks while other targets are left (unset = no limit)
+    #[serde(default)]
+    recent_avoid: Option<usize>,
+    // order of sequential mode: "forward" (default when absent), "reverse" or "pingpong"
+    #[serde(default)]
+    sequential_direction: Option<String>,
+    // visit the parent folders of the targets in turn, one image per folder each tick
+    #[serde(default)]
+    group_by_folder: bool,
+    // random mode picks a parent folder uniformly first, then an image inside it
+    #[serde(default)]
+    folder_weighted: bool,
+    // relative weight of each image in random mode (absent = 1, 0 = never picked)
+    #[serde(default)]
+    weights: HashMap<PathBuf, u32>,
+    // don't change the wallpaper while a fullscreen app is in front (Windows only)
+    #[serde(default)]
+    pause_when_fullscreen: bool,
+    // interval is multiplied by this while running on battery power (unset = no change)
+    #[serde(default)]
+    battery_interval_multiplier: Option<f64>,
+    // launch at login (kept in sync with the OS registration by save_config)
+    #[serde(default)]
+    start_on_login: bool,
+    // start in the tray without showing the window (takes precedence over the saved minimized state)
+    #[serde(default)]
+    start_hidden: bool,
+    // run without the window at all (tray + rotation only); also --headless
+    #[serde(default)]
+    headless: bool,
+    // the window's close button hides to the tray (false = quit like the tray's 閉じる)
+    #[serde(default = "default_true")]
+    close_to_tray: bool,
+    // ask before quitting from the tray's 閉じる
+    #[serde(default)]
+    confirm_on_quit: bool,
+    // quit (restoring the wallpaper) after running this long, e.g. for kiosks / demos
+    #[serde(default)]
+    auto_quit_after_secs: Option<u64>,
+    // false = time spent paused doesn't count toward auto_quit_after_secs
+    #[serde(default = "default_true")]
+    auto_quit_counts_paused: bool,
 }
 
 fn default_interval() -> u64 {
     60
 }
 
+fn interval_unit_secs(unit: Option<&str>) -> Option<u64> {
+    match unit.map(|u| u.to_ascii_lowercase()).as_deref() {
+        None | Some("seconds") => Some(1),
+        Some("minutes") => Some(60),
+        Some("hours") => Some(60 * 60),
+        _ => None,
+    }
+}
+
+// interval_unit を反映した秒数（0 になる場合は従来どおり 60 秒）
+// 選択中のプレイリストに interval があればそれを同じ単位で使う
+fn effective_interval_secs(cfg: &AppConfig) -> u64 {
+    let unit = interval_unit_secs(cfg.interval_unit.as_deref()).unwrap_or(1);
+    let interval = active_playlist(cfg)
+        .and_then(|p| p.interval)
+        .unwrap_or(cfg.interval);
+    match interval.saturating_mul(unit) {
+        0 => 60,
+        secs => secs,
+    }
+}
+
+fn jittered_interval<R: Rng + ?Sized>(base: u64, jitter: u64, rng: &mut R) -> u64 {
+    if jitter == 0 {
+        return base.max(1);
+    }
+    // 0..=2*jitter を引いて jitter を引けば ±jitter の一様分布になる
+    let delta = rng.gen_range(0..=jitter.saturating_mul(2));
+    base.saturating_add(delta).saturating_sub(jitter).max(1)
+}
+
 fn default_random() -> bool {
     true
 }
 
+fn default_auto_rotate() -> bool {
+    true
+}
+
+// 名前付きの画像セット。interval / random は指定があればトップレベルの設定より優先する
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct Playlist {
+    #[serde(default)]
+    targets: Vec<PathBuf>,
+    #[serde(default)]
+    interval: Option<u64>,
+    #[serde(default)]
+    random: Option<bool>,
+}
+
+// 時間帯の開始時刻（時）。night は翌朝の morning まで続く
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+struct TimeSlotHours {
+    morning: u32,
+    afternoon: u32,
+    evening: u32,
+    night: u32,
+}
+
+impl Default for TimeSlotHours {
+    fn default() -> Self {
+        Self {
+            morning: 5,
+            afternoon: 12,
+            evening: 17,
+            night: 21,
+        }
+    }
+}
+
+// ウィンドウの位置・サイズ（window_state.json）。ドラッグのたびに設定ファイル全体を
+// 書き直さないよう別のファイルに保存する。alias は設定ファイルに入っていた頃のキー名
+#[derive(Serialize, Deserialize, Clone, Debug, Default, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct WindowState {
+    // size in logical pixels
+    #[serde(default, alias = "windowWidth")]
+    width: Option<u32>,
+    #[serde(default, alias = "windowHeight")]
+    height: Option<u32>,
+    #[serde(default, alias = "windowMinimized")]
+    minimized: Option<bool>,
+    #[serde(default, alias = "windowMaximized")]
+    maximized: Option<bool>,
+    // position in physical pixels
+    #[serde(default, alias = "windowX")]
+    x: Option<i32>,
+    #[serde(default, alias = "windowY")]
+    y: Option<i32>,
+}
+
+// 設定ファイルの形式のバージョン。形式を変えたら上げて migrate_config に移行処理を足す
+const CONFIG_VERSION: u32 = 2;
+
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
+// 古い形式の設定ファイルを段階的に今の形式へ直してから読み込む（version が無ければ 1）
+fn migrate_config(mut value: serde_json::Value) -> Result<AppConfig, String> {
+    let version = value.get("version").and_then(|v| v.as_u64()).unwrap_or(1);
+
+    // 1 → 2: プレイリストを画像の配列から { targets, interval, random } にした
+    if version < 2 {
+        if let Some(playlists) = value.get_mut("playlists").and_then(|p| p.as_object_mut()) {
+            for playlist in playlists.values_mut() {
+                if playlist.is_array() {
+                    *playlist = serde_json::json!({ "targets": playlist.take() });
+                }
+            }
+        }
+    }
+
+    // 新しいバージョンのアプリが書いたものは下げない
+    if version < CONFIG_VERSION as u64 {
+        if let Some(obj) = value.as_object_mut() {
+            obj.insert("version".to_string(), CONFIG_VERSION.into());
+        }
+    }
+    serde_json::from_value(value).map_err(|e| format!("parse error: {}", e))
+}
+
+fn is_toml(path: &Path) -> bool {
+    path.extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("toml"))
+}
+
+// 設定ファイルの場所。config.toml があればそちらを使い、無ければ config.json
+fn config_path(dir: &Path) -> PathBuf {
+    let toml_path = dir.join("config.toml");
+    if toml_path.exists() {
+        toml_path
+    } else {
+        dir.join("config.json")
+    }
+}
+
+// 形式は拡張子で判断する（TOML も一度 JSON の値にしてから migrate_config を通す）
+fn parse_config(path: &Path, content: &str) -> Result<AppConfig, String> {
+    let value = if is_toml(path) {
+        toml::from_str(content).map_err(|e| format!("parse error: {}", e))?
+    } else {
+        serde_json::from_str(content).map_err(|e| format!("parse error: {}", e))?
+    };
+    migrate_config(value)
+}
+
+// dir に cfg を書き出す。config_format で形式を変えたときは前の形式のファイルを消す
+// （残っていると config.toml が優先されて読まれてしまう）
+fn write_config_file(dir: &Path, cfg: &AppConfig) -> Result<(), String> {
+    let current = config_path(dir);
+    let toml = match cfg.config_format.as_deref() {
+        Some(format) => format.eq_ignore_ascii_case("toml"),
+        None => is_toml(&current),
+    };
+    let path = dir.join(if toml { "config.toml" } else { "config.json" });
+    let content = if toml {
+        toml::to_string_pretty(cfg).map_err(|e| format!("serialize error: {}", e))?
+    } else {
+        serde_json::to_string_pretty(cfg).map_err(|e| format!("serialize error: {}", e))?
+    };
+    write_atomic(&path, &content)?;
+    if current != path && current.exists() {
+        info!("config format changed: removing {}", current.display());
+        fs::remove_file(&current).map_err(|e| format!("remove error: {}", e))?;
+    }
+    Ok(())
+}
+
+fn active_playlist(cfg: &AppConfig) -> Option<&Playlist> {
+    cfg.active_playlist
+        .as_ref()
+        .and_then(|name| cfg.playlists.get(name))
+}
+
+// 選択中のプレイリストに random の指定があればそちらを使う
+fn effective_random(cfg: &AppConfig) -> bool {
+    active_playlist(cfg)
+        .and_then(|p| p.random)
+        .unwrap_or(cfg.random)
+}
+
+fn default_true() -> bool {
+    true
+}
+
+fn default_history_limit() -> usize {
+    100
+}
+
+// 壁紙設定に失敗したときの再試行間隔（ミリ秒）。成功時は待たない
+const SET_RETRY_DELAYS_MS: [u64; 2] = [150, 400];
+
+// validate_before_set で壊れた画像を飛ばす回数の上限（1回の切替あたり）
+const MAX_SKIPS_PER_STEP: usize = 5;
+
+// 設定に失敗した画像を飛ばして次を試す回数の上限（1回の切替あたり）。
+// OS 側が壊れているときに全画像で再試行を待ち続けないようにする
+const MAX_FAILURES_PER_STEP: usize = 3;
+
+// ウィンドウの移動・リサイズ後、設定を書き込むまで待つ時間
+const WINDOW_SAVE_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// 全画面アプリのために切替を見送っているときの再確認間隔
+const FULLSCREEN_RECHECK_SECS: u64 = 15;
+
+// サムネイルのキャッシュを残す期間と最大の枚数（起動時に超えた分を消す）
+const THUMBNAIL_CACHE_MAX_AGE: Duration = Duration::from_secs(30 * 24 * 60 * 60);
+const THUMBNAIL_CACHE_LIMIT: usize = 5_000;
+
+// 画像サイズのキャッシュの上限。超えたら一度空にする
+const DIMENSION_CACHE_LIMIT: usize = 10_000;
+
+// folder_targets を走査し直す間隔（監視できなかった変更もこの間隔で拾う）
+const FOLDER_SCAN_TTL: Duration = Duration::from_secs(60);
+
+// フォルダー・設定ファイル監視のイベントをまとめる時間
+const FOLDER_WATCH_DEBOUNCE: Duration = Duration::from_millis(500);
+
+// 表示回数に変更があれば stats.json に書き出す間隔（切替のたびには書かない）
+const STATS_FLUSH_INTERVAL: Duration = Duration::from_secs(30);
+// 設定の変更をまとめて書き出す間隔
+const CONFIG_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+// log.txt がこのサイズを超えたら log.old.txt に移して書き直す
+const LOG_MAX_BYTES: u64 = 1024 * 1024;
+
+// history_limit に関わらず履歴はこの件数を超えて保持しない
+const MAX_HISTORY: usize = 10_000;
+
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             interval: default_interval(),
+            interval_unit: None,
+            interval_jitter_secs: None,
+            cron: None,
             start_dt: None,
             end_dt: None,
+            dnd_start: None,
+            dnd_end: None,
             weekly: None,
             monthly: None,
+            monthly_clamp: false,
+            monthly_patterns: None,
+            log_level: None,
+            config_format: None,
             default_wallpaper_path: None,
+            initial_wallpaper: None,
             file_targets: Vec::new(),
+            folder_targets: Vec::new(),
+            scan_max_depth: None,
+            include_hidden: false,
+            max_targets: None,
+            playlists: HashMap::new(),
+            active_playlist: None,
+            tags: HashMap::new(),
+            active_tags: Vec::new(),
             random: default_random(),
-            window_width: None,
-            window_height: None,
-            window_minimized: None,
+            virtual_desktop_targets: HashMap::new(),
+            history_limit: default_history_limit(),
+            persist_history: false,
+            random_seed: None,
+            daily_mode: false,
+            auto_rotate: default_auto_rotate(),
+            restore_on_quit: true,
+            exit_wallpaper_path: None,
+            hotkey_next: None,
+            hotkey_prev: None,
+            hotkey_toggle_pause: None,
+            paused: false,
+            restore_outside_schedule: true,
+            respect_external_changes: false,
+            set_lockscreen: false,
+            fallback_color: None,
+            time_collections: None,
+            time_slot_hours: TimeSlotHours::default(),
+            light_targets: Vec::new(),
+            dark_targets: Vec::new(),
+            latitude: None,
+            longitude: None,
+            validate_before_set: false,
+            dry_run: false,
+            recent_avoid: None,
+            sequential_direction: None,
+            group_by_folder: false,
+            folder_weighted: false,
+            weights: HashMap::new(),
+            pause_when_fullscreen: false,
+            battery_interval_multiplier: None,
+            start_on_login: false,
+            start_hidden: false,
+            headless: false,
+            close_to_tray: true,
+            confirm_on_quit: false,
+            auto_quit_after_secs: None,
+            auto_quit_counts_paused: true,
         }
     }
 }
 
-struct AppState {
-    initial_wallpaper: Mutex<Option<PathBuf>>,
-    config: Mutex<AppConfig>,
-    random_active: Mutex<bool>,
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PreviewItem {
+    index: usize,
+    path: String,
+    data_url: String,
+    prev_index: usize,
+    next_index: usize,
+    total: usize,
+}
+
+// 「12 / 340」のような進捗表示用
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct RotationStatus {
+    total: usize,
+    current_index: Option<usize>,
+    current_path: Option<String>,
+    random: bool,
+    paused: bool,
+    // seconds until snooze ends (None = not snoozed)
+    snooze_remaining_secs: Option<u64>,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HistoryEntry {
+    shown_at: DateTime<Local>,
+    path: PathBuf,
+    // "random" / "sequential" / "daily" / "restore" / "manual" ("unknown" for migrated entries)
+    mode: String,
+    // active playlist at the time (None = default file_targets)
+    #[serde(default)]
+    playlist: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WallpaperChanged {
+    path: String,
+    index: Option<usize>,
+    random: bool,
+    // dry_run: picked but not actually set (emitted as dry-run-pick instead)
+    #[serde(skip)]
+    dry_run: bool,
+    // desktop was set but the lock screen failed (emitted as lockscreen-error)
+    #[serde(skip)]
+    lockscreen_error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct WallpaperError {
+    path: String,
+    message: String,
+    // 設定前の検証で読めずに飛ばした（wallpaper-skip として通知する）
+    #[serde(skip)]
+    skipped: bool,
+}
+
+#[derive(Debug, Clone, Serialize)]
+#[serde(rename_all = "camelCase")]
+struct HotkeyError {
+    shortcut: String,
+    message: String,
+}
+
+// トレイメニューのうち状態に応じて表示を変える項目
+struct TrayItems {
+    toggle_pause: MenuItem<tauri::Wry>,
+    active_icon: Image<'static>,
+    idle_icon: Image<'static>,
+    // 今表示しているアイコン（切替時だけ set_icon する）
+    showing_active: Mutex<bool>,
+}
+
+const IDLE_TOOLTIP: &str = "Wallpaper Changer (idle)";
+// Windows のツールチップは128文字までなので長いファイル名は切り詰める
+const TOOLTIP_MAX_CHARS: usize = 60;
+
+// 停止中用のアイコン。通常のアイコンをグレースケールにして半透明にする
+fn idle_icon_from(icon: &Image<'_>) -> Image<'static> {
+    let rgba = icon
+        .rgba()
+        .chunks_exact(4)
+        .flat_map(|px| {
+            let gray =
+                ((px[0] as u32 * 299 + px[1] as u32 * 587 + px[2] as u32 * 114) / 1000) as u8;
+            [gray, gray, gray, px[3] / 2]
+        })
+        .collect();
+    Image::new_owned(rgba, icon.width(), icon.height())
+}
+
+fn pause_label(paused: bool) -> &'static str {
+    if paused {
+        "再開"
+    } else {
+        "一時停止"
+    }
+}
+
+// group_by_folder で最後に表示したフォルダーと、順番切替でのフォルダー内の次の位置
+#[derive(Default, Clone, PartialEq)]
+struct FolderCursor {
+    folder: Option<PathBuf>,
+    positions: HashMap<PathBuf, usize>,
+}
+
+// 切替の位置。ループと next / prev などのコマンドがまとめて読み書きするので1つのロックにする。
+// ロックの順番: config が要るときは先に取って値を写し、離してから rotation を取る
+// （rotation を持ったまま config を取らない）。壁紙の設定中は rotation を持たない
+// （step_wallpaper は写しを取って選択・設定し、終わってから変わった値だけ書き戻す）。
+// 位置を作り直すコマンドは step_lock も取って、切替中の書き戻しで消されないようにする
+#[derive(Default, Clone)]
+struct RotationState {
+    // one of our images is up (restored on exit / outside the schedule)
+    random_active: bool,
     // remember what the last saved/known 'random' setting was so we can detect toggles
-    last_random_enabled: Mutex<bool>,
+    last_random_enabled: bool,
     // when sequential mode is in use, track the next index to show
-    current_index: Mutex<Option<usize>>,
+    current_index: Option<usize>,
     // remember last shown file (used to compute index when switching from random->sequential)
-    last_shown: Mutex<Option<PathBuf>>,
+    last_shown: Option<PathBuf>,
+    // pingpong order is currently walking back towards the start
+    pingpong_reverse: bool,
+    // folder rotation position for group_by_folder
+    folder_cursor: FolderCursor,
+    // fallback_color was put up because there was nothing else to show (shown once until
+    // targets appear again)
+    fallback_shown: bool,
+}
+
+struct TargetsCache {
+    generation: u64,
+    desktop_id: Option<String>,
+    time_slot: Option<&'static str>,
+    // folder_targets were included (then the cache also expires with FOLDER_SCAN_TTL)
+    has_folders: bool,
+    built_at: Instant,
+    targets: Arc<Vec<PathBuf>>,
+}
+
+struct FolderScan {
+    folders: Vec<PathBuf>,
+    scan: ScanOptions,
+    scanned_at: Instant,
+    files: Vec<PathBuf>,
+}
+
+// 画像サイズ（幅・高さ）と、読んだときのファイルの更新日時
+type CachedDimensions = (SystemTime, (u32, u32));
+
+struct AppState {
+    initial_wallpaper: Mutex<Option<PathBuf>>,
+    config: Mutex<AppConfig>,
+    // rotation position (see RotationState for the lock order)
+    rotation: Mutex<RotationState>,
+    // last virtual desktop id seen by the desktop-switch watcher
+    last_desktop_id: Mutex<Option<String>>,
+    // OS light / dark theme reported by the main window (None = unknown, e.g. headless)
+    os_theme: Mutex<Option<tauri::Theme>>,
+    // wallpapers shown so far (oldest first), bounded by history_limit
+    history_log: Mutex<Vec<HistoryEntry>>,
+    // shared generator for every random pick (seeded from random_seed when set)
+    rng: Mutex<StdRng>,
+    // date the daily_mode image was last applied for
+    last_daily_date: Mutex<Option<NaiveDate>>,
+    // when the loop will change the wallpaper next (None = nothing scheduled)
+    next_change_at: Mutex<Option<DateTime<Local>>>,
+    // most recent set_wallpaper failure, for display in the UI
+    last_error: Mutex<Option<String>>,
+    // image sizes read from file headers with the modified time they were read at
+    // (one entry per path, at most DIMENSION_CACHE_LIMIT entries)
+    dimension_cache: Mutex<HashMap<PathBuf, CachedDimensions>>,
+    // last expansion of folder_targets (refreshed after FOLDER_SCAN_TTL)
+    folder_scan: Mutex<Option<FolderScan>>,
+    // last result of effective_targets (rebuilt when targets_generation moves)
+    targets_cache: Mutex<Option<TargetsCache>>,
+    // bumped whenever something effective_targets depends on changes
+    targets_generation: AtomicU64,
+    // filesystem watcher for folder_targets (dropping it stops watching)
+    folder_watcher: Mutex<Option<Debouncer<RecommendedWatcher>>>,
+    // watcher reloading the config file after external edits
+    config_watcher: Mutex<Option<Debouncer<RecommendedWatcher>>>,
+    // current window geometry (kept in window_state.json)
+    window_state: Mutex<WindowState>,
+    // bumped on every window move/resize; only the last one within the debounce writes
+    window_save_generation: AtomicU64,
+    // when the app started (for auto_quit_after_secs)
+    started_at: Instant,
+    // how many times each target has been set as wallpaper (kept in stats.json)
+    show_counts: Mutex<HashMap<PathBuf, u64>>,
+    // show_counts changed since the last flush
+    stats_dirty: AtomicBool,
+    // config changed in memory but not written yet (see flush_config)
+    config_dirty: AtomicBool,
+    // held while flushing so the flush on exit waits for an in-flight periodic one
+    config_flush: Mutex<()>,
+    // last random picks, newest at the back (at most recent_avoid entries)
+    recent_paths: Mutex<VecDeque<PathBuf>>,
+    // held for a whole step_wallpaper (selection + set) so steps never interleave
+    step_lock: Mutex<()>,
+    // rotation is suspended until this time (set by the snooze command, not persisted)
+    snooze_until: Mutex<Option<DateTime<Local>>>,
+    // wakes the loop to re-evaluate; it only changes the wallpaper when one of the flags below
+    // is set or the next change is due
     notify: Notify,
+    // change the wallpaper on the next wake-up (desktop / theme / playlist switch)
+    advance_requested: AtomicBool,
+    // interval settings changed: restart the wait with the new interval without changing now
+    reschedule_requested: AtomicBool,
+    // the exit wallpaper handling already ran (auto quit and ExitRequested both reach it)
+    exit_handled: AtomicBool,
 }
 
 impl AppState {
-    fn new(initial_wallpaper: Option<PathBuf>, config: AppConfig) -> Self {
+    fn new(
+        initial_wallpaper: Option<PathBuf>,
+        config: AppConfig,
+        window_state: WindowState,
+    ) -> Self {
+        let history = if config.persist_history {
+            load_history()
+        } else {
+            Vec::new()
+        };
+
         Self {
             initial_wallpaper: Mutex::new(initial_wallpaper),
             config: Mutex::new(config.clone()),
-            random_active: Mutex::new(false),
-            last_random_enabled: Mutex::new(config.random),
-            current_index: Mutex::new(None),
-            last_shown: Mutex::new(None),
+            rotation: Mutex::new(RotationState {
+                last_random_enabled: effective_random(&config),
+                ..RotationState::default()
+            }),
+            last_desktop_id: Mutex::new(None),
+            os_theme: Mutex::new(None),
+            history_log: Mutex::new(history),
+            rng: Mutex::new(make_rng(config.random_seed)),
+            last_daily_date: Mutex::new(None),
+            next_change_at: Mutex::new(None),
+            last_error: Mutex::new(None),
+            dimension_cache: Mutex::new(HashMap::new()),
+            folder_scan: Mutex::new(None),
+            targets_cache: Mutex::new(None),
+            targets_generation: AtomicU64::new(0),
+            folder_watcher: Mutex::new(None),
+            config_watcher: Mutex::new(None),
+            window_state: Mutex::new(window_state),
+            window_save_generation: AtomicU64::new(0),
+            started_at: Instant::now(),
+            show_counts: Mutex::new(load_stats()),
+            stats_dirty: AtomicBool::new(false),
+            config_dirty: AtomicBool::new(false),
+            config_flush: Mutex::new(()),
+            recent_paths: Mutex::new(VecDeque::new()),
+            step_lock: Mutex::new(()),
+            snooze_until: Mutex::new(None),
             notify: Notify::new(),
+            advance_requested: AtomicBool::new(false),
+            reschedule_requested: AtomicBool::new(false),
+            exit_handled: AtomicBool::new(false),
         }
     }
 }
@@ -105,16 +817,14 @@ impl AppState {
 fn load_config_from_exe_dir() -> AppConfig {
     let exe_path = std::env::current_exe().expect("failed to get current_exe");
     let exe_dir = exe_path.parent().unwrap();
-    let config_path = exe_dir.join("config.json");
+    let config_path = config_path(exe_dir);
 
     if !config_path.exists() {
-        // config.json が無い場合は default を作成して保存 ---
-        eprintln!("config.json not found. Creating default config.");
+        // 設定ファイルが無い場合は default を作成して保存 ---
+        info!("config file not found. Creating default config.");
 
         let default_cfg = AppConfig::default();
-        if let Ok(json) = serde_json::to_string_pretty(&default_cfg) {
-            let _ = std::fs::write(&config_path, json);
-        }
+        let _ = write_config_file(exe_dir, &default_cfg);
 
         return default_cfg;
     }
@@ -122,203 +832,3599 @@ fn load_config_from_exe_dir() -> AppConfig {
     let content = match fs::read_to_string(&config_path) {
         Ok(c) => c,
         Err(e) => {
-            eprintln!("failed to read config.json: {e}");
+            error!("failed to read {}: {e}", config_path.display());
             return AppConfig::default();
         }
     };
 
-    serde_json::from_str(&content).unwrap_or_else(|e| {
-        eprintln!("failed to parse config.json: {e}");
+    parse_config(&config_path, &content).unwrap_or_else(|e| {
+        error!("failed to parse {}: {e}", config_path.display());
         AppConfig::default()
     })
 }
 
-fn weekday_str_to_enum(s: &str) -> Option<Weekday> {
-    match s.to_ascii_lowercase().as_str() {
-        "sun" => Some(Weekday::Sun),
-        "mon" => Some(Weekday::Mon),
-        "tue" | "tues" => Some(Weekday::Tue),
-        "wed" => Some(Weekday::Wed),
-        "thu" | "thur" | "thurs" => Some(Weekday::Thu),
-        "fri" => Some(Weekday::Fri),
-        "sat" => Some(Weekday::Sat),
-        _ => None,
+fn make_rng(seed: Option<u64>) -> StdRng {
+    match seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
     }
 }
 
-fn parse_hhmm(s: &str) -> Option<NaiveTime> {
-    NaiveTime::parse_from_str(s, "%H:%M").ok()
-}
-
-fn should_run(now: chrono::DateTime<Local>, cfg: &AppConfig, currently_active: bool) -> bool {
-    if let Some(weekly) = &cfg.weekly {
-        let today = now.weekday();
-        if !weekly.iter().any(|w| weekday_str_to_enum(w) == Some(today)) {
-            return false;
-        }
+// その日の壁紙を日付から決定的に選ぶ（再起動しても同じ日なら同じ画像になる）
+fn daily_pick(
+    targets: &[PathBuf],
+    date: NaiveDate,
+    random: bool,
+    seed: Option<u64>,
+) -> Option<&PathBuf> {
+    if targets.is_empty() {
+        return None;
     }
-
-    if let Some(monthly) = &cfg.monthly {
-        if !monthly.iter().any(|d| *d == now.day()) {
-            return false;
-        }
+    let day = date.num_days_from_ce() as u64;
+    if random {
+        let mut rng = StdRng::seed_from_u64(day ^ seed.unwrap_or(0));
+        targets.choose(&mut rng)
+    } else {
+        targets.get((day % targets.len() as u64) as usize)
     }
+}
 
-    let time = now.time();
-    let start = cfg.start_dt.as_deref().and_then(parse_hhmm);
-    let end = cfg.end_dt.as_deref().and_then(parse_hhmm);
+fn remaining_secs(at: Option<DateTime<Local>>) -> Option<u64> {
+    at.map(|at| (at - Local::now()).num_seconds().max(0) as u64)
+}
 
-    if currently_active {
-        // 動作中: 終了条件のみチェック
-        if let Some(end_time) = end {
-            let stop = match start {
-                Some(start_time) if start_time > end_time => {
-                    // 日またぎ: [end_time, start_time) のギャップにいれば停止
-                    time > end_time && time < start_time
-                }
-                _ => {
-                    // 同日: end_time を過ぎたら停止
-                    time > end_time
-                }
-            };
-            if stop {
-                return false;
-            }
-        }
-    } else {
-        // 停止中: 開始ウィンドウに入っているかチェック
-        match (start, end) {
-            (Some(s), Some(e)) if s <= e => {
-                // 同日範囲 [s, e]
-                if time < s || time > e {
-                    return false;
-                }
-            }
-            (Some(s), Some(e)) => {
-                // 日またぎ範囲: time >= s OR time <= e の外なら停止
-                if time < s && time > e {
-                    return false;
-                }
-            }
-            (Some(s), None) => {
-                // 開始のみ: s 以降であれば動作
-                if time < s {
-                    return false;
-                }
-            }
-            _ => {
-                // 条件なし: 常に動作
-            }
-        }
-    }
+// 次の周期を待たずに切り替えさせる（設定の保存などで起こすだけなら notify だけでよい）
+fn request_advance(state: &AppState) {
+    state.advance_requested.store(true, Ordering::SeqCst);
+    state.notify.notify_one();
+}
 
-    true
+// 壁紙は変えずに、次の切替時刻を今の間隔で決め直させる
+fn request_reschedule(state: &AppState) {
+    state.reschedule_requested.store(true, Ordering::SeqCst);
+    state.notify.notify_one();
 }
 
-fn get_current_wallpaper() -> Option<PathBuf> {
-    match wallpaper::get() {
-        Ok(path_str) => Some(PathBuf::from(path_str)),
-        Err(e) => {
-            eprintln!("failed to get current wallpaper: {e}");
-            None
-        }
+// 次回の切替予定を更新し、変わったときだけ next-change-in（残り秒数 / null）を通知する
+fn set_next_change(app_handle: &tauri::AppHandle, at: Option<DateTime<Local>>) {
+    let state = app_handle.state::<AppState>();
+    let mut next = state.next_change_at.lock().unwrap();
+    if *next != at {
+        *next = at;
+        let _ = app_handle.emit("next-change-in", remaining_secs(at));
     }
 }
 
-fn set_wallpaper(path: &Path) {
-    //println!("set wallpaper: {}", path.to_string_lossy());
-    if let Err(e) = wallpaper::set_from_path(path.to_string_lossy().as_ref()) {
-        eprintln!("failed to set wallpaper: {e}");
-    }
+// 間隔での切替を今回行うか（切替の要求・実行時間帯に入った直後・予定時刻を過ぎた）
+fn interval_change_due(
+    state: &AppState,
+    now: DateTime<Local>,
+    advance_requested: bool,
+    was_active: bool,
+) -> bool {
+    advance_requested
+        || !was_active
+        || state
+            .next_change_at
+            .lock()
+            .unwrap()
+            .is_none_or(|at| now >= at)
 }
 
-fn is_image_file(path: &Path) -> bool {
-    if let Some(ext) = path.extension().and_then(|e| e.to_str()) {
-        matches!(
-            ext.to_ascii_lowercase().as_str(),
-            "jpg" | "jpeg" | "png" | "bmp" | "gif" | "webp"
+// 間隔での次の切替時刻。選択と同じ乱数源で揺らす（random_seed があれば待ち時間も再現される）
+fn next_interval_change(
+    state: &AppState,
+    now: DateTime<Local>,
+    interval_secs: u64,
+    jitter_secs: u64,
+) -> DateTime<Local> {
+    let wait = jittered_interval(interval_secs, jitter_secs, &mut *state.rng.lock().unwrap());
+    now + chrono::Duration::seconds(wait.min(i32::MAX as u64) as i64)
+}
+
+fn next_midnight(now: DateTime<Local>) -> Option<DateTime<Local>> {
+    now.date_naive()
+        .succ_opt()?
+        .and_hms_opt(0, 0, 0)?
+        .and_local_timezone(Local)
+        .earliest()
+}
+
+fn log_path() -> Option<PathBuf> {
+    let exe_path = std::env::current_exe().ok()?;
+    Some(exe_path.parent()?.join("log.txt"))
+}
+
+fn log_level_filter(level: Option<&str>) -> Option<LevelFilter> {
+    match level {
+        Some("error") => Some(LevelFilter::Error),
+        Some("warn") => Some(LevelFilter::Warn),
+        None | Some("info") => Some(LevelFilter::Info),
+        Some("debug") => Some(LevelFilter::Debug),
+        Some("trace") => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+// ウィンドウアプリでは標準エラーが見えないので log.txt にも書く
+struct FileLogger {
+    path: PathBuf,
+    file: Mutex<Option<fs::File>>,
+}
+
+impl FileLogger {
+    fn open(path: &Path) -> Option<fs::File> {
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .ok()
+    }
+}
+
+impl log::Log for FileLogger {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        // 依存クレートのログは警告以上だけ
+        metadata.level() <= log::max_level()
+            && (metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+                || metadata.level() <= log::Level::Warn)
+    }
+
+    fn log(&self, record: &log::Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let line = format!(
+            "{} {:<5} {}\n",
+            Local::now().format("%Y-%m-%d %H:%M:%S"),
+            record.level(),
+            record.args()
+        );
+        eprint!("{line}");
+
+        let mut file = self.file.lock().unwrap();
+        // サイズ上限を超えたら1世代だけ残して書き直す
+        let full = file
+            .as_ref()
+            .and_then(|f| f.metadata().ok())
+            .is_some_and(|m| m.len() >= LOG_MAX_BYTES);
+        if full {
+            *file = None;
+            let _ = fs::rename(&self.path, self.path.with_file_name("log.old.txt"));
+        }
+        if file.is_none() {
+            *file = FileLogger::open(&self.path);
+        }
+        if let Some(f) = file.as_mut() {
+            let _ = f.write_all(line.as_bytes());
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(f) = self.file.lock().unwrap().as_mut() {
+            let _ = f.flush();
+        }
+    }
+}
+
+// 設定を読む前のログも残せるよう main の最初に呼ぶ（レベルは設定を読んだ後に合わせる）
+fn init_logging() {
+    let Some(path) = log_path() else {
+        return;
+    };
+    let logger = FileLogger {
+        file: Mutex::new(FileLogger::open(&path)),
+        path,
+    };
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(LevelFilter::Info);
+    }
+}
+
+fn history_path() -> Option<PathBuf> {
+    let exe_path = std::env::current_exe().ok()?;
+    Some(exe_path.parent()?.join("history.json"))
+}
+
+// history.json の1件。以前は [表示日時, パス] の配列で保存していたので、その形式も読めるようにする
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum StoredHistoryEntry {
+    Entry(HistoryEntry),
+    Legacy(DateTime<Local>, PathBuf),
+}
+
+impl From<StoredHistoryEntry> for HistoryEntry {
+    fn from(stored: StoredHistoryEntry) -> Self {
+        match stored {
+            StoredHistoryEntry::Entry(entry) => entry,
+            StoredHistoryEntry::Legacy(shown_at, path) => HistoryEntry {
+                shown_at,
+                path,
+                mode: "unknown".to_string(),
+                playlist: None,
+            },
+        }
+    }
+}
+
+fn load_history() -> Vec<HistoryEntry> {
+    let Some(path) = history_path() else {
+        return Vec::new();
+    };
+    if !path.exists() {
+        return Vec::new();
+    }
+
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str::<Vec<StoredHistoryEntry>>(&content)
+            .map(|entries| entries.into_iter().map(HistoryEntry::from).collect())
+            .unwrap_or_else(|e| {
+                warn!("failed to parse history.json: {e}");
+                Vec::new()
+            }),
+        Err(e) => {
+            warn!("failed to read history.json: {e}");
+            Vec::new()
+        }
+    }
+}
+
+// 表示した壁紙を履歴に追加する（上限を超えた古いものから捨てる）
+fn push_history(state: &AppState, path: &Path, mode: &str) {
+    let (limit, persist, playlist) = {
+        let cfg = state.config.lock().unwrap();
+        (
+            cfg.history_limit.min(MAX_HISTORY),
+            cfg.persist_history,
+            cfg.active_playlist.clone(),
+        )
+    };
+
+    let mut history = state.history_log.lock().unwrap();
+    history.push(HistoryEntry {
+        shown_at: Local::now(),
+        path: path.to_path_buf(),
+        mode: mode.to_string(),
+        playlist,
+    });
+    if history.len() > limit {
+        let overflow = history.len() - limit;
+        history.drain(..overflow);
+    }
+
+    if persist {
+        if let Err(e) = save_history(&history) {
+            error!("failed to write history.json: {e}");
+        }
+    }
+}
+
+fn stats_path() -> Option<PathBuf> {
+    let exe_path = std::env::current_exe().ok()?;
+    Some(exe_path.parent()?.join("stats.json"))
+}
+
+fn load_stats() -> HashMap<PathBuf, u64> {
+    let Some(path) = stats_path() else {
+        return HashMap::new();
+    };
+    if !path.exists() {
+        return HashMap::new();
+    }
+
+    match fs::read_to_string(&path) {
+        Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("failed to parse stats.json: {e}");
+            HashMap::new()
+        }),
+        Err(e) => {
+            warn!("failed to read stats.json: {e}");
+            HashMap::new()
+        }
+    }
+}
+
+fn save_stats(counts: &HashMap<PathBuf, u64>) -> Result<(), String> {
+    let path = stats_path().ok_or("failed to get exe dir")?;
+    let json =
+        serde_json::to_string_pretty(counts).map_err(|e| format!("serialize error: {}", e))?;
+    write_atomic(&path, &json)
+}
+
+// 変更があったときだけ stats.json に書き出す
+fn flush_stats(state: &AppState) {
+    if !state.stats_dirty.swap(false, Ordering::SeqCst) {
+        return;
+    }
+    let counts = state.show_counts.lock().unwrap().clone();
+    if let Err(e) = save_stats(&counts) {
+        error!("failed to write stats.json: {e}");
+    }
+}
+
+fn save_history(history: &[HistoryEntry]) -> Result<(), String> {
+    let path = history_path().ok_or("failed to get exe dir")?;
+    let json =
+        serde_json::to_string_pretty(history).map_err(|e| format!("serialize error: {}", e))?;
+    write_atomic(&path, &json)
+}
+
+// 一時ファイルに書いてから rename することで、途中で落ちても壊れたファイルを残さない
+fn write_atomic(path: &Path, content: &str) -> Result<(), String> {
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, content).map_err(|e| format!("write error: {}", e))?;
+    fs::rename(&tmp_path, path).map_err(|e| {
+        let _ = fs::remove_file(&tmp_path);
+        format!("rename error: {}", e)
+    })
+}
+
+// 数値は日曜始まり（0 = 日曜 … 6 = 土曜、cron と同じ）
+fn weekday_str_to_enum(s: &str) -> Option<Weekday> {
+    match s.to_ascii_lowercase().as_str() {
+        "sun" | "sunday" | "日" | "0" => Some(Weekday::Sun),
+        "mon" | "monday" | "月" | "1" => Some(Weekday::Mon),
+        "tue" | "tues" | "tuesday" | "火" | "2" => Some(Weekday::Tue),
+        "wed" | "wednesday" | "水" | "3" => Some(Weekday::Wed),
+        "thu" | "thur" | "thurs" | "thursday" | "木" | "4" => Some(Weekday::Thu),
+        "fri" | "friday" | "金" | "5" => Some(Weekday::Fri),
+        "sat" | "saturday" | "土" | "6" => Some(Weekday::Sat),
+        _ => None,
+    }
+}
+
+// weekly のうち曜日として解釈できないものを返す
+fn invalid_weekdays(weekly: &[String]) -> Vec<String> {
+    weekly
+        .iter()
+        .filter(|w| weekday_str_to_enum(w).is_none())
+        .cloned()
+        .collect()
+}
+
+fn parse_hhmm(s: &str) -> Option<NaiveTime> {
+    NaiveTime::parse_from_str(s, "%H:%M").ok()
+}
+
+// その日の日の出・日の入り。白夜・極夜の日は一日中昼 / 夜として扱う
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum SunTimes {
+    Daylight(DateTime<Utc>, DateTime<Utc>),
+    AlwaysDay,
+    AlwaysNight,
+}
+
+// 日の出の式（https://en.wikipedia.org/wiki/Sunrise_equation）で計算する。誤差は数分程度
+fn sun_times(date: NaiveDate, latitude: f64, longitude: f64) -> SunTimes {
+    let j2000 = NaiveDate::from_ymd_opt(2000, 1, 1).unwrap();
+    // 経度から求めた南中の平均太陽時（2000-01-01 12:00 UTC からの日数）
+    let mean_solar_noon = (date - j2000).num_days() as f64 - longitude / 360.0;
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon)
+        .rem_euclid(360.0)
+        .to_radians();
+    let center =
+        1.9148 * anomaly.sin() + 0.0200 * (2.0 * anomaly).sin() + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = 2451545.0 + mean_solar_noon + 0.0053 * anomaly.sin()
+        - 0.0069 * (2.0 * ecliptic_longitude).sin();
+    let declination = (ecliptic_longitude.sin() * 23.4397_f64.to_radians().sin()).asin();
+
+    // 太陽の上端が地平線に見える高度（大気差込み）
+    let lat = latitude.to_radians();
+    let cos_hour_angle = ((-0.833_f64).to_radians().sin() - lat.sin() * declination.sin())
+        / (lat.cos() * declination.cos());
+    if cos_hour_angle < -1.0 {
+        return SunTimes::AlwaysDay;
+    }
+    if cos_hour_angle > 1.0 {
+        return SunTimes::AlwaysNight;
+    }
+
+    let half_day = cos_hour_angle.acos().to_degrees() / 360.0;
+    let to_utc = |julian: f64| {
+        let secs = ((julian - 2440587.5) * 86400.0).round() as i64;
+        DateTime::from_timestamp(secs, 0).unwrap_or_default()
+    };
+    SunTimes::Daylight(to_utc(transit - half_day), to_utc(transit + half_day))
+}
+
+fn is_daylight(now: DateTime<Local>, latitude: f64, longitude: f64) -> bool {
+    match sun_times(now.date_naive(), latitude, longitude) {
+        SunTimes::Daylight(sunrise, sunset) => (sunrise..sunset).contains(&now.with_timezone(&Utc)),
+        SunTimes::AlwaysDay => true,
+        SunTimes::AlwaysNight => false,
+    }
+}
+
+// 今の時刻が属する時間帯（time_collections のキー）
+fn current_time_slot(now: NaiveTime, hours: &TimeSlotHours) -> &'static str {
+    let hour = now.hour();
+    if hour >= hours.night || hour < hours.morning {
+        "night"
+    } else if hour >= hours.evening {
+        "evening"
+    } else if hour >= hours.afternoon {
+        "afternoon"
+    } else {
+        "morning"
+    }
+}
+
+// OS のテーマに合わせた画像が設定されていればそれを返す（テーマが分からなければ通常の対象を使う）
+fn targets_for_theme(cfg: &AppConfig, theme: Option<tauri::Theme>) -> Option<Vec<PathBuf>> {
+    let targets = match theme? {
+        tauri::Theme::Light => &cfg.light_targets,
+        tauri::Theme::Dark => &cfg.dark_targets,
+        _ => return None,
+    };
+    Some(targets.clone()).filter(|targets| !targets.is_empty())
+}
+
+// 時間帯用の画像が設定されていればそれを返す（無ければ通常の対象を使う）
+fn targets_for_time_slot(cfg: &AppConfig, slot: Option<&str>) -> Option<Vec<PathBuf>> {
+    slot.and_then(|slot| cfg.time_collections.as_ref()?.get(slot))
+        .filter(|targets| !targets.is_empty())
+        .cloned()
+}
+
+fn days_in_month(date: NaiveDate) -> u32 {
+    let (year, month) = if date.month() == 12 {
+        (date.year() + 1, 1)
+    } else {
+        (date.year(), date.month() + 1)
+    };
+    NaiveDate::from_ymd_opt(year, month, 1)
+        .and_then(|first| first.pred_opt())
+        .map(|last| last.day())
+        .unwrap_or(31)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MonthPattern {
+    // 月末
+    LastDay,
+    // 月最後の平日（月〜金）
+    LastWeekday,
+    // 月最後の指定曜日
+    LastOf(Weekday),
+    // 第 n 指定曜日（1〜5）
+    Nth(u32, Weekday),
+}
+
+fn parse_month_pattern(s: &str) -> Option<MonthPattern> {
+    let s = s.trim().to_ascii_lowercase();
+    match s.as_str() {
+        "last" => return Some(MonthPattern::LastDay),
+        "last-weekday" => return Some(MonthPattern::LastWeekday),
+        _ => {}
+    }
+
+    let (ordinal, day) = s.split_once('-')?;
+    let weekday = weekday_str_to_enum(day)?;
+    match ordinal {
+        "last" => Some(MonthPattern::LastOf(weekday)),
+        "1st" => Some(MonthPattern::Nth(1, weekday)),
+        "2nd" => Some(MonthPattern::Nth(2, weekday)),
+        "3rd" => Some(MonthPattern::Nth(3, weekday)),
+        "4th" => Some(MonthPattern::Nth(4, weekday)),
+        "5th" => Some(MonthPattern::Nth(5, weekday)),
+        _ => None,
+    }
+}
+
+// monthly_patterns の1件が今日に当てはまるか（解釈できないものは当てはまらない扱い）
+fn matches_month_pattern(now: chrono::DateTime<Local>, pattern: &str) -> bool {
+    let date = now.date_naive();
+    let day = date.day();
+    let last_day = days_in_month(date);
+
+    match parse_month_pattern(pattern) {
+        Some(MonthPattern::LastDay) => day == last_day,
+        Some(MonthPattern::LastWeekday) => {
+            let mut last = date.with_day(last_day).unwrap_or(date);
+            while matches!(last.weekday(), Weekday::Sat | Weekday::Sun) {
+                last = last.pred_opt().unwrap_or(last);
+            }
+            date == last
+        }
+        Some(MonthPattern::LastOf(weekday)) => date.weekday() == weekday && day + 7 > last_day,
+        Some(MonthPattern::Nth(n, weekday)) => date.weekday() == weekday && (day - 1) / 7 + 1 == n,
+        None => false,
+    }
+}
+
+// DND の時間帯に入っているか（開始・終了の両方が必要。日またぎは実行時間帯と同じ扱い）
+fn in_dnd_window(time: NaiveTime, cfg: &AppConfig) -> bool {
+    let start = cfg.dnd_start.as_deref().and_then(parse_hhmm);
+    let end = cfg.dnd_end.as_deref().and_then(parse_hhmm);
+    match (start, end) {
+        (Some(s), Some(e)) if s <= e => time >= s && time <= e,
+        (Some(s), Some(e)) => time >= s || time <= e,
+        _ => false,
+    }
+}
+
+fn should_run(now: chrono::DateTime<Local>, cfg: &AppConfig, currently_active: bool) -> bool {
+    // DND は実行時間帯より優先する
+    if in_dnd_window(now.time(), cfg) {
+        return false;
+    }
+
+    if let Some(weekly) = &cfg.weekly {
+        let today = now.weekday();
+        if !weekly.iter().any(|w| weekday_str_to_enum(w) == Some(today)) {
+            return false;
+        }
+    }
+
+    if cfg.monthly.is_some() || cfg.monthly_patterns.is_some() {
+        let today = now.day();
+        let last_day = days_in_month(now.date_naive());
+        let matches = |d: u32| {
+            if cfg.monthly_clamp && d > last_day {
+                today == last_day
+            } else {
+                d == today
+            }
+        };
+        let day_matched = cfg
+            .monthly
+            .as_ref()
+            .is_some_and(|monthly| monthly.iter().any(|d| matches(*d)));
+        let pattern_matched = cfg
+            .monthly_patterns
+            .as_ref()
+            .is_some_and(|patterns| patterns.iter().any(|p| matches_month_pattern(now, p)));
+        if !day_matched && !pattern_matched {
+            return false;
+        }
+    }
+
+    // 位置が設定されていれば日の出〜日の入りの間だけ動かす
+    if let (Some(latitude), Some(longitude)) = (cfg.latitude, cfg.longitude) {
+        return is_daylight(now, latitude, longitude);
+    }
+
+    let time = now.time();
+    let start = cfg.start_dt.as_deref().and_then(parse_hhmm);
+    let end = cfg.end_dt.as_deref().and_then(parse_hhmm);
+
+    if currently_active {
+        // 動作中: 終了条件のみチェック
+        if let Some(end_time) = end {
+            let stop = match start {
+                Some(start_time) if start_time > end_time => {
+                    // 日またぎ: [end_time, start_time) のギャップにいれば停止
+                    time > end_time && time < start_time
+                }
+                _ => {
+                    // 同日: end_time を過ぎたら停止
+                    time > end_time
+                }
+            };
+            if stop {
+                return false;
+            }
+        }
+    } else {
+        // 停止中: 開始ウィンドウに入っているかチェック
+        match (start, end) {
+            (Some(s), Some(e)) if s <= e => {
+                // 同日範囲 [s, e]
+                if time < s || time > e {
+                    return false;
+                }
+            }
+            (Some(s), Some(e)) => {
+                // 日またぎ範囲: time >= s OR time <= e の外なら停止
+                if time < s && time > e {
+                    return false;
+                }
+            }
+            (Some(s), None) => {
+                // 開始のみ: s 以降であれば動作
+                if time < s {
+                    return false;
+                }
+            }
+            _ => {
+                // 条件なし: 常に動作
+            }
+        }
+    }
+
+    true
+}
+
+// 壁紙の取得・設定の窓口。選択ロジックを実際の OS に触れずに動かせるよう差し替え可能にしている
+trait WallpaperSetter {
+    fn set(&self, p: &Path) -> Result<(), String>;
+    fn get(&self) -> Option<PathBuf>;
+}
+
+struct OsWallpaperSetter;
+
+impl WallpaperSetter for OsWallpaperSetter {
+    fn set(&self, p: &Path) -> Result<(), String> {
+        debug!("set wallpaper: {}", p.display());
+        // OS が直接扱えない形式は PNG に変換し、長すぎるパスは短いパスへコピーしてから設定する
+        let prepared = if needs_transcode(p) {
+            Some(transcode_to_png(p)?)
+        } else if needs_short_path(p) {
+            Some(copy_to_short_path(p)?)
+        } else {
+            None
+        };
+        let p = prepared.as_deref().unwrap_or(p);
+        wallpaper::set_from_path(p.to_string_lossy().as_ref()).map_err(|e| {
+            error!("failed to set wallpaper: {e}");
+            e.to_string()
+        })?;
+        // 前回の書き出し結果は OS が使わなくなってから消す（単色の画像も同じフォルダーにある）
+        prune_prepared(&transcode_dir(), p);
+        Ok(())
+    }
+
+    fn get(&self) -> Option<PathBuf> {
+        match wallpaper::get() {
+            Ok(path_str) => {
+                debug!("current wallpaper: {path_str}");
+                Some(PathBuf::from(path_str))
+            }
+            Err(e) => {
+                warn!("failed to get current wallpaper: {e}");
+                None
+            }
+        }
+    }
+}
+
+fn get_current_wallpaper() -> Option<PathBuf> {
+    OsWallpaperSetter.get()
+}
+
+// ロック画面の画像を設定する。エディションやグループポリシーで変更が禁止されていると
+// アクセス拒否のエラーになる
+#[cfg(target_os = "windows")]
+fn set_lockscreen_image(path: &Path) -> Result<(), String> {
+    use windows::core::HSTRING;
+    use windows::Storage::StorageFile;
+    use windows::System::UserProfile::LockScreen;
+
+    let prepared;
+    let path = if needs_transcode(path) {
+        prepared = transcode_to_png(path)?;
+        prepared.as_path()
+    } else {
+        path
+    };
+    let file = StorageFile::GetFileFromPathAsync(&HSTRING::from(path.as_os_str()))
+        .and_then(|op| op.get())
+        .map_err(|e| format!("lock screen error: {}", e))?;
+    LockScreen::SetImageFileAsync(&file)
+        .and_then(|op| op.get())
+        .map_err(|e| format!("lock screen error: {}", e))
+}
+
+// Windows 以外はロック画面の API が無いので何もしない（一度だけログに残す）
+#[cfg(not(target_os = "windows"))]
+fn set_lockscreen_image(_path: &Path) -> Result<(), String> {
+    static NOTED: OnceLock<()> = OnceLock::new();
+    NOTED.get_or_init(|| info!("set_lockscreen is only supported on Windows; ignoring"));
+    Ok(())
+}
+
+// 現在アクティブな仮想デスクトップの ID を返す（前面ウィンドウが属するデスクトップで判定）
+#[cfg(target_os = "windows")]
+fn current_virtual_desktop_id() -> Option<String> {
+    use windows::core::GUID;
+    use windows::Win32::System::Com::{
+        CoCreateInstance, CoInitializeEx, CLSCTX_ALL, COINIT_MULTITHREADED,
+    };
+    use windows::Win32::UI::Shell::{IVirtualDesktopManager, VirtualDesktopManager};
+    use windows::Win32::UI::WindowsAndMessaging::GetForegroundWindow;
+
+    unsafe {
+        // already-initialized apartments return S_FALSE / RPC_E_CHANGED_MODE, both fine here
+        let _ = CoInitializeEx(None, COINIT_MULTITHREADED);
+        let manager: IVirtualDesktopManager =
+            match CoCreateInstance(&VirtualDesktopManager, None, CLSCTX_ALL) {
+                Ok(m) => m,
+                Err(e) => {
+                    warn!("IVirtualDesktopManager unavailable: {e}");
+                    return None;
+                }
+            };
+
+        let hwnd = GetForegroundWindow();
+        if hwnd.is_invalid() {
+            return None;
+        }
+
+        match manager.GetWindowDesktopId(hwnd) {
+            Ok(id) if id != GUID::zeroed() => Some(format!("{:?}", id)),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn current_virtual_desktop_id() -> Option<String> {
+    None
+}
+
+// 全画面のアプリ（ゲーム・プレゼンなど）が前面にあるか
+#[cfg(target_os = "windows")]
+fn fullscreen_app_active() -> bool {
+    use windows::Win32::UI::Shell::{
+        SHQueryUserNotificationState, QUNS_BUSY, QUNS_PRESENTATION_MODE,
+        QUNS_RUNNING_D3D_FULL_SCREEN,
+    };
+
+    match unsafe { SHQueryUserNotificationState() } {
+        Ok(state) => {
+            state == QUNS_BUSY
+                || state == QUNS_RUNNING_D3D_FULL_SCREEN
+                || state == QUNS_PRESENTATION_MODE
+        }
+        Err(_) => false,
+    }
+}
+
+// 判定できない環境では止めない
+#[cfg(not(target_os = "windows"))]
+fn fullscreen_app_active() -> bool {
+    false
+}
+
+// バッテリー駆動中か（判定できなければ false = 電源接続扱い）
+#[cfg(target_os = "windows")]
+fn on_battery_power() -> bool {
+    use windows::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    let mut status = SYSTEM_POWER_STATUS::default();
+    // ACLineStatus: 0 = バッテリー, 1 = 電源接続, 255 = 不明
+    unsafe { GetSystemPowerStatus(&mut status) }.is_ok() && status.ACLineStatus == 0
+}
+
+// pmset の結果を使い回す時間（ループのたびにプロセスを起動しない）
+#[cfg(target_os = "macos")]
+const POWER_STATUS_TTL: Duration = Duration::from_secs(60);
+
+#[cfg(target_os = "macos")]
+fn on_battery_power() -> bool {
+    static CACHE: Mutex<Option<(Instant, bool)>> = Mutex::new(None);
+
+    let mut cache = CACHE.lock().unwrap();
+    if let Some((checked_at, on_battery)) = *cache {
+        if checked_at.elapsed() < POWER_STATUS_TTL {
+            return on_battery;
+        }
+    }
+    let on_battery = std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .map(|out| String::from_utf8_lossy(&out.stdout).contains("'Battery Power'"))
+        .unwrap_or(false);
+    *cache = Some((Instant::now(), on_battery));
+    on_battery
+}
+
+// バッテリーがあって、AC アダプター（Mains）がどれも接続されていなければバッテリー駆動
+#[cfg(not(any(target_os = "windows", target_os = "macos")))]
+fn on_battery_power() -> bool {
+    let Ok(entries) = fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let read = |dir: &Path, name: &str| {
+        fs::read_to_string(dir.join(name))
+            .map(|s| s.trim().to_string())
+            .unwrap_or_default()
+    };
+
+    let mut has_battery = false;
+    for entry in entries.flatten() {
+        let dir = entry.path();
+        match read(&dir, "type").as_str() {
+            "Mains" if read(&dir, "online") == "1" => return false,
+            "Battery" => has_battery = true,
+            _ => {}
+        }
+    }
+    has_battery
+}
+
+// バッテリー駆動中は battery_interval_multiplier 倍に延ばした間隔
+fn power_adjusted_interval(interval_secs: u64, multiplier: Option<f64>) -> u64 {
+    match multiplier {
+        Some(m) if m > 0.0 && on_battery_power() => {
+            ((interval_secs as f64 * m).round() as u64).max(1)
+        }
+        _ => interval_secs,
+    }
+}
+
+// 切替が遅くなっている理由を UI に出すため
+#[tauri::command]
+fn get_power_state() -> bool {
+    on_battery_power()
+}
+
+// スリープ復帰時はタイマーの満了を待たずにループを起こして、時間帯と間隔を計算し直す
+#[cfg(target_os = "windows")]
+fn on_resume(app_handle: &tauri::AppHandle) {
+    let state = app_handle.state::<AppState>();
+    let (active, last_shown) = {
+        let rotation = state.rotation.lock().unwrap();
+        (rotation.random_active, rotation.last_shown.clone())
+    };
+    if active && last_shown.is_some() && get_current_wallpaper() != last_shown {
+        info!("wallpaper was changed externally while asleep");
+    }
+    state.notify.notify_one();
+}
+
+// 電源イベント（スリープからの復帰）の通知を登録する
+#[cfg(target_os = "windows")]
+fn watch_power_events(app_handle: &tauri::AppHandle) {
+    use std::ffi::c_void;
+    use windows::Win32::Foundation::{ERROR_SUCCESS, HANDLE};
+    use windows::Win32::System::Power::{
+        PowerRegisterSuspendResumeNotification, DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS,
+    };
+    use windows::Win32::UI::WindowsAndMessaging::{DEVICE_NOTIFY_CALLBACK, PBT_APMRESUMEAUTOMATIC};
+
+    unsafe extern "system" fn callback(context: *const c_void, kind: u32, _: *const c_void) -> u32 {
+        // 復帰のたびに必ず届くのは PBT_APMRESUMEAUTOMATIC（ユーザー操作での復帰は他のイベントも続く）
+        if kind == PBT_APMRESUMEAUTOMATIC {
+            let app_handle = unsafe { &*(context as *const tauri::AppHandle) };
+            on_resume(app_handle);
+        }
+        ERROR_SUCCESS.0
+    }
+
+    // 登録はアプリ終了まで有効なので、コールバックに渡す値はリークさせて生かしておく
+    let context = Box::into_raw(Box::new(app_handle.clone()));
+    let params = Box::into_raw(Box::new(DEVICE_NOTIFY_SUBSCRIBE_PARAMETERS {
+        Callback: Some(callback),
+        Context: context as *mut c_void,
+    }));
+    let mut registration: *mut c_void = std::ptr::null_mut();
+    let result = unsafe {
+        PowerRegisterSuspendResumeNotification(
+            DEVICE_NOTIFY_CALLBACK,
+            HANDLE(params as *mut c_void),
+            &mut registration,
+        )
+    };
+    if result != ERROR_SUCCESS {
+        warn!("failed to register for resume notifications: {:?}", result);
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+fn watch_power_events(_app_handle: &tauri::AppHandle) {}
+
+// 切替に使う画像かどうか（fileTargets・folderTargets・プレイリストと仮想デスクトップ用の画像を見る）
+// 変換済みの一時 PNG も切替中の画像とみなす
+fn is_rotation_target(cfg: &AppConfig, path: &Path) -> bool {
+    path.starts_with(transcode_dir())
+        || cfg.file_targets.iter().any(|p| p == path)
+        || cfg.folder_targets.iter().any(|dir| path.starts_with(dir))
+        || cfg
+            .playlists
+            .values()
+            .any(|playlist| playlist.targets.iter().any(|p| p == path))
+        || cfg
+            .virtual_desktop_targets
+            .values()
+            .any(|targets| targets.iter().any(|p| p == path))
+        || cfg.light_targets.iter().any(|p| p == path)
+        || cfg.dark_targets.iter().any(|p| p == path)
+        || cfg
+            .time_collections
+            .iter()
+            .flat_map(|collections| collections.values())
+            .any(|targets| targets.iter().any(|p| p == path))
+}
+
+// 仮想デスクトップ用の画像が設定されていればそれを返す（無ければ通常の対象を使う）
+fn targets_for_desktop(cfg: &AppConfig, desktop_id: Option<&str>) -> Option<Vec<PathBuf>> {
+    desktop_id
+        .and_then(|id| cfg.virtual_desktop_targets.get(id))
+        .filter(|targets| !targets.is_empty())
+        .cloned()
+}
+
+// folder_targets 内の画像。走査は重いので同じフォルダー構成なら TTL の間は使い回す
+fn folder_target_files(state: &AppState, folders: &[PathBuf], scan: ScanOptions) -> Vec<PathBuf> {
+    if folders.is_empty() {
+        return Vec::new();
+    }
+
+    if let Some(cached) = state.folder_scan.lock().unwrap().as_ref().filter(|s| {
+        s.folders == folders && s.scan == scan && s.scanned_at.elapsed() < FOLDER_SCAN_TTL
+    }) {
+        return cached.files.clone();
+    }
+
+    // 走査中はロックを持たない（監視スレッドやループが待たされないように）。
+    // read_dir の順序は OS 任せなので、順番切替が安定するよう並べ替える
+    let mut files: Vec<PathBuf> = folders
+        .iter()
+        .flat_map(|dir| collect_images_recursively(dir, scan))
+        .collect();
+    files.sort();
+    files.dedup();
+    *state.folder_scan.lock().unwrap() = Some(FolderScan {
+        folders: folders.to_vec(),
+        scan,
+        scanned_at: Instant::now(),
+        files: files.clone(),
+    });
+    files
+}
+
+// 監視イベントのパスが切替対象の増減にあたるか。known は前回走査した画像（並べ替え済み）。
+// 既にある画像の更新は存在と一覧が一致するので対象外になる
+fn is_target_change(path: &Path, known: &[PathBuf]) -> bool {
+    let listed = known.binary_search_by(|p| p.as_path().cmp(path)).is_ok();
+    if is_image_file(path) {
+        return path.is_file() != listed;
+    }
+    // フォルダーごと移動・削除された場合は中の画像のイベントが来ないことがある
+    let has_known_files = known.iter().any(|p| p.starts_with(path));
+    if path.is_dir() {
+        !has_known_files
+    } else {
+        has_known_files
+    }
+}
+
+// folder_targets を監視し、画像の追加・削除があればすぐに走査し直してループを起こす。
+// まとめてコピーした場合などの連続したイベントは debouncer が1回にまとめる
+fn watch_folder_targets(app_handle: &tauri::AppHandle, folders: &[PathBuf]) {
+    let state = app_handle.state::<AppState>();
+    // 古い監視は先に止める
+    *state.folder_watcher.lock().unwrap() = None;
+    if folders.is_empty() {
+        return;
+    }
+
+    let handle = app_handle.clone();
+    let watched = folders.to_vec();
+    let debouncer = new_debouncer(
+        FOLDER_WATCH_DEBOUNCE,
+        move |res: DebounceEventResult| match res {
+            Ok(events) => {
+                let state = handle.state::<AppState>();
+                // 内容やメタデータの更新（サムネイル生成やウイルス対策ソフトの読み書きなど）は無視し、
+                // 画像やフォルダーの追加・削除・名前変更のときだけ走査し直す
+                let changed = {
+                    let scan = state.folder_scan.lock().unwrap();
+                    let known = scan
+                        .as_ref()
+                        .map(|s| s.files.as_slice())
+                        .unwrap_or_default();
+                    events
+                        .iter()
+                        .any(|event| is_target_change(&event.path, known))
+                };
+                if !changed {
+                    return;
+                }
+                *state.folder_scan.lock().unwrap() = None;
+                invalidate_targets(&state);
+                let scan = ScanOptions::from_config(&state.config.lock().unwrap());
+                folder_target_files(&state, &watched, scan);
+                // 一覧を作り直させるだけで、切替の時刻は変えない
+                state.notify.notify_one();
+            }
+            Err(e) => warn!("folder watch error: {e}"),
+        },
+    );
+    let mut debouncer = match debouncer {
+        Ok(debouncer) => debouncer,
+        Err(e) => {
+            warn!("failed to start folder watcher: {e}");
+            return;
+        }
+    };
+
+    for dir in folders {
+        if let Err(e) = debouncer.watcher().watch(dir, RecursiveMode::Recursive) {
+            warn!("failed to watch {}: {e}", dir.display());
+        }
+    }
+    *state.folder_watcher.lock().unwrap() = Some(debouncer);
+}
+
+// 設定やフォルダーの中身が変わったら呼ぶ。次の effective_targets で一覧を作り直す
+fn invalidate_targets(state: &AppState) {
+    state.targets_generation.fetch_add(1, Ordering::SeqCst);
+}
+
+// 今切り替え対象になっている画像一覧。ループが毎回呼ぶので、設定が変わるまでは
+// 前回作った一覧を共有する（数万枚あってもポインタの複製だけで済む）
+fn effective_targets(state: &AppState) -> Arc<Vec<PathBuf>> {
+    // 仮想デスクトップの判定は config のロック外で行う
+    let (has_desktop_targets, time_slot) = {
+        let cfg = state.config.lock().unwrap();
+        let time_slot = cfg
+            .time_collections
+            .as_ref()
+            .filter(|collections| !collections.is_empty())
+            .map(|_| current_time_slot(Local::now().time(), &cfg.time_slot_hours));
+        (!cfg.virtual_desktop_targets.is_empty(), time_slot)
+    };
+    let desktop_id = if has_desktop_targets {
+        current_virtual_desktop_id()
+    } else {
+        None
+    };
+
+    let generation = state.targets_generation.load(Ordering::SeqCst);
+    if let Some(cached) = state.targets_cache.lock().unwrap().as_ref().filter(|c| {
+        c.generation == generation
+            && c.desktop_id == desktop_id
+            && c.time_slot == time_slot
+            && !(c.has_folders && c.built_at.elapsed() >= FOLDER_SCAN_TTL)
+    }) {
+        return cached.targets.clone();
+    }
+
+    let (targets, has_folders) = build_effective_targets(state, desktop_id.as_deref(), time_slot);
+    let targets = Arc::new(targets);
+    *state.targets_cache.lock().unwrap() = Some(TargetsCache {
+        generation,
+        desktop_id,
+        time_slot,
+        has_folders,
+        built_at: Instant::now(),
+        targets: targets.clone(),
+    });
+    targets
+}
+
+// 仮想デスクトップ用 → プレイリスト → テーマ用 → 時間帯用 → file_targets + folder_targets の順に決め、
+// タグで絞り込む。2つ目はフォルダーを走査したか
+fn build_effective_targets(
+    state: &AppState,
+    desktop_id: Option<&str>,
+    time_slot: Option<&str>,
+) -> (Vec<PathBuf>, bool) {
+    let theme = *state.os_theme.lock().unwrap();
+    let (mut targets, folders, scan) = {
+        let cfg = state.config.lock().unwrap();
+        if let Some(targets) = targets_for_desktop(&cfg, desktop_id) {
+            return (targets, false);
+        }
+        // プレイリスト選択中はその画像だけを使う（削除済みの名前なら通常の対象に戻る）
+        let (targets, folders) = match active_playlist(&cfg) {
+            Some(playlist) => (playlist.targets.clone(), Vec::new()),
+            None => match targets_for_theme(&cfg, theme)
+                .or_else(|| targets_for_time_slot(&cfg, time_slot))
+            {
+                Some(targets) => (targets, Vec::new()),
+                None => (cfg.file_targets.clone(), cfg.folder_targets.clone()),
+            },
+        };
+        (targets, folders, ScanOptions::from_config(&cfg))
+    };
+
+    // 個別に選んだ画像の後ろにフォルダー内の画像を続ける
+    let chosen: HashSet<PathBuf> = targets.iter().cloned().collect();
+    targets.extend(
+        folder_target_files(state, &folders, scan)
+            .into_iter()
+            .filter(|file| !chosen.contains(file)),
+    );
+
+    // タグで絞り込む（仮想デスクトップ用の画像は対象外）
+    let cfg = state.config.lock().unwrap();
+    if !cfg.active_tags.is_empty() {
+        targets.retain(|path| {
+            cfg.tags
+                .get(path)
+                .is_some_and(|tags| tags.iter().any(|t| cfg.active_tags.contains(t)))
+        });
+    }
+    (targets, !folders.is_empty())
+}
+
+// 切替対象から外れた画像のタグ・重みを消す
+fn prune_image_settings(cfg: &mut AppConfig) {
+    let stale: Vec<PathBuf> = cfg
+        .tags
+        .keys()
+        .chain(cfg.weights.keys())
+        .filter(|path| !is_rotation_target(cfg, path))
+        .cloned()
+        .collect();
+    for path in stale {
+        cfg.tags.remove(&path);
+        cfg.weights.remove(&path);
+    }
+}
+
+// 順番切替で i の次の位置（back = true なら前の位置）。
+// pingpong は端で向きを変え、その向きを reverse に残す
+fn sequential_step(i: usize, len: usize, direction: &str, reverse: &mut bool, back: bool) -> usize {
+    if len < 2 {
+        return 0;
+    }
+    let backward = match direction {
+        "reverse" => !back,
+        "pingpong" => *reverse != back,
+        _ => back,
+    };
+    if direction == "pingpong" {
+        if backward && i == 0 {
+            *reverse = !*reverse;
+            return 1;
+        }
+        if !backward && i + 1 >= len {
+            *reverse = !*reverse;
+            return len - 2;
+        }
+    }
+    if backward {
+        (i + len - 1) % len
+    } else {
+        (i + 1) % len
+    }
+}
+
+// 親フォルダーごとにまとめる（フォルダーも画像も targets に出てくる順）
+fn group_by_parent(targets: &[PathBuf]) -> Vec<(PathBuf, Vec<usize>)> {
+    let mut groups: Vec<(PathBuf, Vec<usize>)> = Vec::new();
+    for (i, path) in targets.iter().enumerate() {
+        let dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        match groups.iter_mut().find(|(d, _)| *d == dir) {
+            Some((_, members)) => members.push(i),
+            None => groups.push((dir, vec![i])),
+        }
+    }
+    groups
+}
+
+fn image_weight(weights: &HashMap<PathBuf, u32>, path: &Path) -> u32 {
+    weights.get(path).copied().unwrap_or(1)
+}
+
+// 候補から weights に従って1枚選ぶ
+fn choose_weighted_image<R: Rng + ?Sized>(
+    targets: &[PathBuf],
+    candidates: &[usize],
+    weights: &HashMap<PathBuf, u32>,
+    rng: &mut R,
+) -> Option<usize> {
+    candidates
+        .choose_weighted(rng, |i| image_weight(weights, &targets[*i]))
+        .ok()
+        .copied()
+}
+
+// ランダム切替で次に試す画像を選ぶ（tried は失敗して除外する画像）
+fn pick_random<R: Rng + ?Sized>(
+    targets: &[PathBuf],
+    tried: &[usize],
+    recent: &VecDeque<PathBuf>,
+    weights: &HashMap<PathBuf, u32>,
+    folder_weighted: bool,
+    rng: &mut R,
+) -> Option<usize> {
+    // 重み 0 の画像は候補にしない
+    let remaining: Vec<usize> = (0..targets.len())
+        .filter(|i| !tried.contains(i) && image_weight(weights, &targets[*i]) > 0)
+        .collect();
+    // 直近 recent_avoid 回に出した画像は避ける（他に候補が無ければ避けない）
+    let fresh: Vec<usize> = remaining
+        .iter()
+        .copied()
+        .filter(|i| !recent.contains(&targets[*i]))
+        .collect();
+    let remaining = if fresh.is_empty() { remaining } else { fresh };
+    // folder_weighted は recent_avoid で絞った残りの中でフォルダーを均等に選ぶ
+    // weights はフォルダーを選んだ後、フォルダー内の画像の選択に使う
+    if folder_weighted {
+        choose_folder_weighted(targets, &remaining, weights, rng)
+    } else {
+        choose_weighted_image(targets, &remaining, weights, rng)
+    }
+}
+
+// 候補の親フォルダーを等確率で選び、その中から1枚選ぶ（画像の多いフォルダーに偏らない）
+fn choose_folder_weighted<R: Rng + ?Sized>(
+    targets: &[PathBuf],
+    candidates: &[usize],
+    weights: &HashMap<PathBuf, u32>,
+    rng: &mut R,
+) -> Option<usize> {
+    let paths: Vec<PathBuf> = candidates.iter().map(|i| targets[*i].clone()).collect();
+    let groups = group_by_parent(&paths);
+    let (_, members) = groups.choose(rng)?;
+    let members: Vec<usize> = members.iter().map(|k| candidates[*k]).collect();
+    choose_weighted_image(targets, &members, weights, rng)
+}
+
+// group_by_folder: 前回のフォルダーの次（forward = false なら前）のフォルダーから1枚選んで設定する。
+// フォルダー内はランダムか順番。前回のフォルダーが対象から消えていたら先頭のフォルダーから始める
+fn step_by_folder(
+    setter: &dyn WallpaperSetter,
+    state: &AppState,
+    cursor: &mut FolderCursor,
+    targets: &[PathBuf],
+    showing: Option<&Path>,
+    random: bool,
+    forward: bool,
+) -> (Option<PathBuf>, Vec<SetOutcome>) {
+    let validate = state.config.lock().unwrap().validate_before_set;
+    let mut outcomes = Vec::new();
+    let groups = group_by_parent(targets);
+    let count = groups.len();
+    let mode = if random { "random" } else { "sequential" };
+    let mut skips = 0;
+
+    cursor
+        .positions
+        .retain(|dir, _| groups.iter().any(|(d, _)| d == dir));
+    let start = match cursor
+        .folder
+        .as_ref()
+        .and_then(|f| groups.iter().position(|(d, _)| d == f))
+    {
+        Some(pos) if forward => (pos + 1) % count,
+        Some(pos) => (pos + count - 1) % count,
+        None => 0,
+    };
+
+    // 設定に失敗したらフォルダー内の他の画像、それも駄目なら次のフォルダーを試す
+    for attempt in 0..count {
+        let g = if forward {
+            (start + attempt) % count
+        } else {
+            (start + count - attempt) % count
+        };
+        let (dir, members) = &groups[g];
+        let order: Vec<usize> = if random {
+            let mut shuffled = members.clone();
+            shuffled.shuffle(&mut *state.rng.lock().unwrap());
+            shuffled
+        } else {
+            let pos = cursor.positions.get(dir).copied().unwrap_or(0) % members.len();
+            members[pos..]
+                .iter()
+                .chain(&members[..pos])
+                .copied()
+                .collect()
+        };
+
+        for index in order {
+            let path = &targets[index];
+            if let Some(skip) = skip_invalid(validate, path) {
+                outcomes.push(skip);
+                skips += 1;
+                if skips >= MAX_SKIPS_PER_STEP {
+                    return (None, outcomes);
+                }
+                continue;
+            }
+            let outcome = apply_wallpaper(setter, state, path, showing, Some(index), random, mode);
+            let applied = outcome.is_ok();
+            outcomes.push(outcome);
+            if applied {
+                cursor.folder = Some(dir.clone());
+                if let Some(pos) = members.iter().position(|i| *i == index) {
+                    cursor
+                        .positions
+                        .insert(dir.clone(), (pos + 1) % members.len());
+                }
+                return (Some(path.clone()), outcomes);
+            }
+            if too_many_failures(&outcomes) {
+                return (None, outcomes);
+            }
+        }
+    }
+    (None, outcomes)
+}
+
+fn too_many_failures(outcomes: &[SetOutcome]) -> bool {
+    outcomes
+        .iter()
+        .filter(|outcome| matches!(outcome, Err(error) if !error.skipped))
+        .count()
+        >= MAX_FAILURES_PER_STEP
+}
+
+// ランダム / 逐次で次（forward = false なら前）の壁紙を選んで設定する。ループと next/prev コマンドで共用
+// 戻り値は表示できた画像と、試した順の設定結果（呼び出し側で emit_outcomes に渡す）
+fn step_wallpaper(
+    setter: &dyn WallpaperSetter,
+    state: &AppState,
+    targets: &[PathBuf],
+    random: bool,
+    forward: bool,
+) -> (Option<PathBuf>, Vec<SetOutcome>) {
+    if targets.is_empty() {
+        return (None, Vec::new());
+    }
+    // ループと next / prev が同時に選ばないよう順番にする。設定中も持つのはこのロックだけなので
+    // save_config など他のコマンドは壁紙の設定を待たない
+    let _stepping = state.step_lock.lock().unwrap();
+    let before = state.rotation.lock().unwrap().clone();
+    let mut rotation = before.clone();
+    let result = select_and_set(setter, state, targets, random, forward, &mut rotation);
+    // 設定中に apply_config などが書き換えた値を消さないよう、この切替で変わった値だけ書き戻す
+    // （random_active はループが切り替える値なので触らない）
+    let mut current = state.rotation.lock().unwrap();
+    if rotation.last_random_enabled != before.last_random_enabled {
+        current.last_random_enabled = rotation.last_random_enabled;
+    }
+    if rotation.current_index != before.current_index {
+        current.current_index = rotation.current_index;
+    }
+    if rotation.last_shown != before.last_shown {
+        current.last_shown = rotation.last_shown;
+    }
+    if rotation.pingpong_reverse != before.pingpong_reverse {
+        current.pingpong_reverse = rotation.pingpong_reverse;
+    }
+    if rotation.folder_cursor != before.folder_cursor {
+        current.folder_cursor = rotation.folder_cursor;
+    }
+    result
+}
+
+fn select_and_set(
+    setter: &dyn WallpaperSetter,
+    state: &AppState,
+    targets: &[PathBuf],
+    random: bool,
+    forward: bool,
+    pos: &mut RotationState,
+) -> (Option<PathBuf>, Vec<SetOutcome>) {
+    let mut outcomes = Vec::new();
+    let (validate, recent_avoid, direction, group_by_folder, folder_weighted, weights) = {
+        let cfg = state.config.lock().unwrap();
+        (
+            cfg.validate_before_set,
+            cfg.recent_avoid.unwrap_or(0),
+            cfg.sequential_direction
+                .clone()
+                .unwrap_or_else(|| "forward".to_string()),
+            cfg.group_by_folder,
+            cfg.folder_weighted,
+            cfg.weights.clone(),
+        )
+    };
+    let mut skips = 0;
+    // OS への問い合わせは切替1回につき1回だけ（試す画像ごとには聞かない）
+    let showing = setter.get().or_else(|| pos.last_shown.clone());
+
+    if group_by_folder {
+        pos.current_index = None;
+        pos.last_random_enabled = random;
+        let (shown, outcomes) = step_by_folder(
+            setter,
+            state,
+            &mut pos.folder_cursor,
+            targets,
+            showing.as_deref(),
+            random,
+            forward,
+        );
+        if let Some(path) = &shown {
+            pos.last_shown = Some(path.clone());
+        }
+        return (shown, outcomes);
+    }
+
+    if random {
+        // random mode: pick randomly and remember last shown; clear sequential index
+        pos.current_index = None;
+        pos.last_random_enabled = true;
+
+        // 設定に失敗した画像は除いて選び直す
+        let mut tried: Vec<usize> = Vec::new();
+        while tried.len() < targets.len() {
+            let index = if forward {
+                let recent = state.recent_paths.lock().unwrap();
+                let mut rng = state.rng.lock().unwrap();
+                let picked = pick_random(
+                    targets,
+                    &tried,
+                    &recent,
+                    &weights,
+                    folder_weighted,
+                    &mut *rng,
+                );
+                match picked {
+                    Some(i) => i,
+                    None => break,
+                }
+            } else if tried.is_empty() {
+                // 「前へ」はひとつ前に表示していた画像に戻る
+                let history = state.history_log.lock().unwrap();
+                let previous = history
+                    .iter()
+                    .rev()
+                    .filter(|entry| entry.mode != "restore")
+                    .map(|entry| &entry.path)
+                    .filter(|p| Some(*p) != pos.last_shown.as_ref())
+                    .find_map(|p| targets.iter().position(|t| t == p));
+                match previous {
+                    Some(i) => i,
+                    None => break,
+                }
+            } else {
+                break;
+            };
+            tried.push(index);
+
+            let choice = &targets[index];
+            if let Some(skip) = skip_invalid(validate, choice) {
+                outcomes.push(skip);
+                skips += 1;
+                if skips >= MAX_SKIPS_PER_STEP {
+                    break;
+                }
+                continue;
+            }
+            let outcome = apply_wallpaper(
+                setter,
+                state,
+                choice,
+                showing.as_deref(),
+                Some(index),
+                true,
+                "random",
+            );
+            let applied = outcome.is_ok();
+            outcomes.push(outcome);
+            if applied {
+                pos.last_shown = Some(choice.clone());
+                let mut recent = state.recent_paths.lock().unwrap();
+                recent.push_back(choice.clone());
+                while recent.len() > recent_avoid {
+                    recent.pop_front();
+                }
+                return (Some(choice.clone()), outcomes);
+            }
+            if too_many_failures(&outcomes) {
+                break;
+            }
+        }
+        return (None, outcomes);
+    }
+
+    let len = targets.len();
+
+    // sequential mode: if we just toggled from random -> sequential,
+    // start from the next index after the last shown image
+    if pos.last_random_enabled && pos.current_index.is_none() {
+        let current = pos.last_shown.clone().or_else(|| setter.get());
+        pos.current_index = Some(
+            current
+                .and_then(|c| targets.iter().position(|p| *p == c))
+                .map(|i| sequential_step(i, len, &direction, &mut pos.pingpong_reverse, false))
+                .unwrap_or(0),
+        );
+    }
+
+    // update remembered flag: we're now in sequential mode
+    pos.last_random_enabled = false;
+
+    // current_index は「次に表示する」位置なので、前へは2つ戻る（pingpong の向きは戻すときだけ変える）
+    let next = pos.current_index.unwrap_or(0) % len;
+    let mut reverse = pos.pingpong_reverse;
+    let start = if forward {
+        next
+    } else {
+        let shown = sequential_step(next, len, &direction, &mut reverse, true);
+        sequential_step(shown, len, &direction, &mut reverse, true)
+    };
+
+    // 設定に失敗したら同じ向きに次の画像を試す（一周したら諦める）
+    let mut i = start;
+    for attempt in 0..len {
+        if attempt > 0 {
+            i = sequential_step(i, len, &direction, &mut reverse, !forward);
+        }
+        let path = &targets[i];
+        if let Some(skip) = skip_invalid(validate, path) {
+            outcomes.push(skip);
+            skips += 1;
+            if skips >= MAX_SKIPS_PER_STEP {
+                break;
+            }
+            continue;
+        }
+        let outcome = apply_wallpaper(
+            setter,
+            state,
+            path,
+            showing.as_deref(),
+            Some(i),
+            false,
+            "sequential",
+        );
+        let applied = outcome.is_ok();
+        outcomes.push(outcome);
+        if applied {
+            pos.last_shown = Some(path.clone());
+            pos.current_index = Some(sequential_step(i, len, &direction, &mut reverse, false));
+            pos.pingpong_reverse = reverse;
+            return (Some(path.clone()), outcomes);
+        }
+        if too_many_failures(&outcomes) {
+            break;
+        }
+    }
+
+    pos.current_index = Some(sequential_step(
+        start,
+        len,
+        &direction,
+        &mut pos.pingpong_reverse,
+        false,
+    ));
+    (None, outcomes)
+}
+
+fn set_wallpaper(path: &Path) -> Result<(), String> {
+    OsWallpaperSetter.set(path)
+}
+
+// 一時的な失敗（スリープ復帰直後やレジストリの競合など）に備えて、Err のときだけ少し待って再試行する。
+// ブロッキングするので async な文脈からは spawn_blocking 経由で呼ぶこと
+fn retry_with_backoff<F>(mut attempt: F) -> Result<(), String>
+where
+    F: FnMut() -> Result<(), String>,
+{
+    let mut result = attempt();
+    for delay in SET_RETRY_DELAYS_MS {
+        if result.is_ok() {
+            break;
+        }
+        std::thread::sleep(Duration::from_millis(delay));
+        result = attempt();
+    }
+    result
+}
+
+// 設定に成功したら wallpaper-changed、失敗したら wallpaper-error として通知する内容
+type SetOutcome = Result<WallpaperChanged, WallpaperError>;
+
+// ヘッダーだけ読んで画像として開けるか確かめる（HEIC は image crate で読めないので対象外）
+fn validate_image(path: &Path) -> Result<(), String> {
+    if extension_lowercase(path).is_some_and(|ext| ext == "heic" || ext == "heif") {
+        return Ok(());
+    }
+    image::ImageReader::open(path)
+        .map_err(|e| format!("read error: {}", e))?
+        .with_guessed_format()
+        .map_err(|e| format!("read error: {}", e))?
+        .into_dimensions()
+        .map_err(|e| format!("decode error: {}", e))?;
+    Ok(())
+}
+
+fn skip_invalid(validate: bool, path: &Path) -> Option<SetOutcome> {
+    if !validate {
+        return None;
+    }
+    let message = validate_image(path).err()?;
+    warn!("skipping unreadable image {}: {message}", path.display());
+    Some(Err(WallpaperError {
+        path: path.to_string_lossy().to_string(),
+        message,
+        skipped: true,
+    }))
+}
+
+// 壁紙を設定し、成功したときだけ履歴に残す。失敗は last_error に記録する
+fn apply_wallpaper(
+    setter: &dyn WallpaperSetter,
+    state: &AppState,
+    path: &Path,
+    showing: Option<&Path>,
+    index: Option<usize>,
+    random: bool,
+    mode: &str,
+) -> SetOutcome {
+    // dry_run では設定したことにして位置・履歴だけ進める（表示回数は数えない）
+    let dry_run = state.config.lock().unwrap().dry_run;
+    if dry_run {
+        info!("dry run ({mode}): {}", path.display());
+        push_history(state, path, mode);
+        return Ok(WallpaperChanged {
+            path: path.to_string_lossy().to_string(),
+            index,
+            random,
+            dry_run,
+            lockscreen_error: None,
+        });
+    }
+
+    // 同じ画像をもう一度設定するとちらつく環境があるので、表示中なら設定はせず位置・履歴だけ進める
+    // （showing は呼び出し側が切替1回につき1回だけ showing_wallpaper で取る）
+    let mut lockscreen_error = None;
+    if showing.is_some_and(|current| same_path(current, path)) {
+        debug!("already showing {}, skipping set", path.display());
+    } else if let Err(message) = retry_with_backoff(|| setter.set(path)) {
+        let path = path.to_string_lossy().to_string();
+        *state.last_error.lock().unwrap() = Some(format!("{}: {}", path, message));
+        return Err(WallpaperError {
+            path,
+            message,
+            skipped: false,
+        });
+    }
+    // デスクトップの設定を省いた場合もロック画面は別に変わっていることがあるので設定する
+    if state.config.lock().unwrap().set_lockscreen {
+        // ロック画面は失敗してもデスクトップの切替は成功として扱い、エラーだけ通知する
+        if let Err(message) = set_lockscreen_image(path) {
+            warn!("failed to set lock screen: {message}");
+            *state.last_error.lock().unwrap() = Some(format!("{}: {}", path.display(), message));
+            lockscreen_error = Some(message);
+        }
+    }
+
+    push_history(state, path, mode);
+    // 元の壁紙に戻した分は数えない
+    if mode != "restore" {
+        *state
+            .show_counts
+            .lock()
+            .unwrap()
+            .entry(path.to_path_buf())
+            .or_default() += 1;
+        state.stats_dirty.store(true, Ordering::SeqCst);
+    }
+    Ok(WallpaperChanged {
+        path: path.to_string_lossy().to_string(),
+        index,
+        random,
+        dry_run,
+        lockscreen_error,
+    })
+}
+
+// 今表示中の壁紙。外から変えられている場合もあるので、取れるなら実際の壁紙を使う
+fn showing_wallpaper(setter: &dyn WallpaperSetter, state: &AppState) -> Option<PathBuf> {
+    setter
+        .get()
+        .or_else(|| state.rotation.lock().unwrap().last_shown.clone())
+}
+
+// 表記の違い（区切り文字や大文字小文字、相対パスなど）で別物と判定しないよう正規化して比べる
+fn same_path(a: &Path, b: &Path) -> bool {
+    if a == b {
+        return true;
+    }
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => false,
+    }
+}
+
+fn emit_outcomes(app_handle: &tauri::AppHandle, outcomes: &[SetOutcome]) {
+    for outcome in outcomes {
+        if let Ok(WallpaperChanged {
+            path,
+            lockscreen_error: Some(message),
+            ..
+        }) = outcome
+        {
+            let error = WallpaperError {
+                path: path.clone(),
+                message: message.clone(),
+                skipped: false,
+            };
+            let _ = app_handle.emit("lockscreen-error", &error);
+        }
+        let _ = match outcome {
+            Ok(changed) if changed.dry_run => app_handle.emit("dry-run-pick", &changed.path),
+            Ok(changed) => app_handle.emit("wallpaper-changed", changed),
+            Err(error) if error.skipped => app_handle.emit("wallpaper-skip", error),
+            Err(error) => app_handle.emit("wallpaper-error", error),
+        };
+    }
+}
+
+// 1枚だけ設定して結果をフロントへ通知する（復元や日替わりなど選択ロジックを通さない場合）
+fn apply_and_emit(
+    app_handle: &tauri::AppHandle,
+    path: &Path,
+    index: Option<usize>,
+    random: bool,
+    mode: &str,
+) -> bool {
+    let state = app_handle.state::<AppState>();
+    let showing = showing_wallpaper(&OsWallpaperSetter, &state);
+    let outcome = apply_wallpaper(
+        &OsWallpaperSetter,
+        &state,
+        path,
+        showing.as_deref(),
+        index,
+        random,
+        mode,
+    );
+    let applied = outcome.is_ok();
+    emit_outcomes(app_handle, &[outcome]);
+    applied
+}
+
+// ループから呼ぶ版。壁紙の設定は環境によって時間がかかるので、ループの async タスクを
+// 塞がないよう spawn_blocking で実行する
+async fn step_wallpaper_blocking(
+    app_handle: &tauri::AppHandle,
+    targets: Arc<Vec<PathBuf>>,
+    random: bool,
+) -> (Option<PathBuf>, Vec<SetOutcome>) {
+    let handle = app_handle.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let state = handle.state::<AppState>();
+        step_wallpaper(&OsWallpaperSetter, &state, &targets, random, true)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        error!("wallpaper step failed: {e}");
+        (None, Vec::new())
+    })
+}
+
+async fn apply_and_emit_blocking(
+    app_handle: &tauri::AppHandle,
+    path: PathBuf,
+    index: Option<usize>,
+    random: bool,
+    mode: &'static str,
+) -> bool {
+    let handle = app_handle.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        apply_and_emit(&handle, &path, index, random, mode)
+    })
+    .await
+    .unwrap_or_else(|e| {
+        error!("wallpaper set failed: {e}");
+        false
+    })
+}
+
+// 切替対象として認識する拡張子（小文字）。AVIF は image crate が既定の機能では読めないので含めない。
+// HEIC も読めないので、OS が直接扱える Windows / macOS だけ対象にする
+const IMAGE_EXTENSIONS: &[&str] = if cfg!(any(target_os = "windows", target_os = "macos")) {
+    &[
+        "jpg", "jpeg", "png", "bmp", "gif", "webp", "tif", "tiff", "heic", "heif",
+    ]
+} else {
+    &["jpg", "jpeg", "png", "bmp", "gif", "webp", "tif", "tiff"]
+};
+
+// このうち OS にそのまま渡せる拡張子。それ以外は PNG に変換して設定する
+// （HEIC は image crate で読めないので Windows / macOS の対応に任せる）
+const NATIVE_EXTENSIONS: &[&str] = if cfg!(any(target_os = "windows", target_os = "macos")) {
+    &["jpg", "jpeg", "png", "bmp", "gif", "webp", "heic", "heif"]
+} else {
+    &["jpg", "jpeg", "png", "bmp", "gif", "webp"]
+};
+
+fn extension_lowercase(path: &Path) -> Option<String> {
+    path.extension()
+        .and_then(|e| e.to_str())
+        .map(|e| e.to_ascii_lowercase())
+}
+
+// フロントのファイル選択のフィルターを is_image_file と揃えるため IMAGE_EXTENSIONS をそのまま返す
+#[tauri::command]
+fn get_supported_extensions() -> Vec<String> {
+    IMAGE_EXTENSIONS.iter().map(|ext| ext.to_string()).collect()
+}
+
+fn is_image_file(path: &Path) -> bool {
+    extension_lowercase(path).is_some_and(|ext| IMAGE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+fn needs_transcode(path: &Path) -> bool {
+    extension_lowercase(path).is_some_and(|ext| !NATIVE_EXTENSIONS.contains(&ext.as_str()))
+}
+
+fn transcode_dir() -> PathBuf {
+    std::env::temp_dir().join("wallpaper_changer")
+}
+
+// Windows の壁紙 API は MAX_PATH (260) を超えるパスや拡張パス形式 (\\?\) を受け付けず、
+// wallpaper crate は &str でしか受け取らないので UTF-8 にできないパスも渡せない。
+// 読み込み・走査は std が長いパスを自動で拡張形式にするのでそのままでよい
+fn needs_short_path(path: &Path) -> bool {
+    (cfg!(target_os = "windows") && path.as_os_str().len() >= 260) || path.to_str().is_none()
+}
+
+// 一時フォルダー内の書き出し先。同じパスだと OS が更新しないことがあるので元のパスごとに名前を変える
+// （前回の書き出し結果は表示中の壁紙なので、ここでは消さずに設定できてから prune_prepared で消す）
+fn prepared_path(path: &Path, ext: &str) -> Result<PathBuf, String> {
+    let dir = transcode_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("write error: {}", e))?;
+
+    let mut hasher = DefaultHasher::new();
+    path.hash(&mut hasher);
+    Ok(dir.join(format!("{:016x}.{}", hasher.finish(), ext)))
+}
+
+// 壁紙を設定できた後で、一時フォルダー（dir）のうち今の壁紙（keep）以外を消す
+fn prune_prepared(dir: &Path, keep: &Path) {
+    let Ok(entries) = fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path != keep {
+            if let Err(e) = fs::remove_file(&path) {
+                debug!("failed to remove {}: {e}", path.display());
+            }
+        }
+    }
+}
+
+fn copy_to_short_path(path: &Path) -> Result<PathBuf, String> {
+    let out = prepared_path(path, &extension_lowercase(path).unwrap_or_default())?;
+    fs::copy(path, &out).map_err(|e| format!("copy error: {}", e))?;
+    Ok(out)
+}
+
+fn transcode_to_png(path: &Path) -> Result<PathBuf, String> {
+    let img = image::open(path).map_err(|e| format!("decode error: {}", e))?;
+    let out = prepared_path(path, "png")?;
+    img.save_with_format(&out, image::ImageFormat::Png)
+        .map_err(|e| format!("encode error: {}", e))?;
+    Ok(out)
+}
+
+// フォルダー走査の設定（AppConfig から取り出す）
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+struct ScanOptions {
+    max_depth: Option<usize>,
+    include_hidden: bool,
+}
+
+impl ScanOptions {
+    fn from_config(cfg: &AppConfig) -> Self {
+        Self {
+            max_depth: cfg.scan_max_depth,
+            include_hidden: cfg.include_hidden,
+        }
+    }
+}
+
+fn collect_images_recursively(path: &Path, scan: ScanOptions) -> Vec<PathBuf> {
+    let mut result = Vec::new();
+
+    if path.is_file() {
+        if is_image_file(path) {
+            result.push(path.to_path_buf());
+        }
+    } else if path.is_dir() {
+        collect_dir_images(path, scan, 0, &mut HashSet::new(), &mut result);
+    }
+
+    result
+}
+
+// .thumbnails などのドットで始まるものと、Windows の隠し属性が付いたもの
+fn is_hidden(entry: &fs::DirEntry) -> bool {
+    entry.file_name().to_string_lossy().starts_with('.') || has_hidden_attribute(entry)
+}
+
+#[cfg(target_os = "windows")]
+fn has_hidden_attribute(entry: &fs::DirEntry) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+
+    entry
+        .metadata()
+        .is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0)
+}
+
+#[cfg(not(target_os = "windows"))]
+fn has_hidden_attribute(_entry: &fs::DirEntry) -> bool {
+    false
+}
+
+// シンボリックリンクで循環していても止まるよう、正規化したパスで一度見たフォルダーは飛ばす
+fn collect_dir_images(
+    dir: &Path,
+    scan: ScanOptions,
+    depth: usize,
+    visited: &mut HashSet<PathBuf>,
+    result: &mut Vec<PathBuf>,
+) {
+    let canonical = dir.canonicalize().unwrap_or_else(|_| dir.to_path_buf());
+    if !visited.insert(canonical) {
+        return;
+    }
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return;
+    };
+    for entry in entries.flatten() {
+        if !scan.include_hidden && is_hidden(&entry) {
+            continue;
+        }
+        let p = entry.path();
+        if p.is_dir() {
+            if scan.max_depth.is_none_or(|max| depth < max) {
+                collect_dir_images(&p, scan, depth + 1, visited, result);
+            }
+        } else if is_image_file(&p) {
+            result.push(p);
+        }
+    }
+}
+
+// 長辺が max_edge に収まるよう縮小した JPEG のバイト列を返す（data URL にするのは thumbnail_data_url）
+fn thumbnail_jpeg(path: &Path, max_edge: u32) -> Result<Vec<u8>, String> {
+    let img = image::open(path).map_err(|e| format!("decode error: {}", e))?;
+    let edge = max_edge.max(1);
+    let thumb = img.thumbnail(edge, edge).to_rgb8();
+
+    let mut buf = Cursor::new(Vec::new());
+    thumb
+        .write_to(&mut buf, image::ImageFormat::Jpeg)
+        .map_err(|e| format!("encode error: {}", e))?;
+    Ok(buf.into_inner())
+}
+
+// サムネイルは exe と同じ場所の thumbnails/ にキャッシュする。
+// キーはパス・更新日時・サイズのハッシュなので、画像が変われば作り直される
+fn thumbnail_dir() -> Option<PathBuf> {
+    let exe_path = std::env::current_exe().ok()?;
+    Some(exe_path.parent()?.join("thumbnails"))
+}
+
+// FNV-1a (64bit)。DefaultHasher は Rust のバージョンで結果が変わりうるので、
+// ディスクに残すキャッシュの名前にはこちらを使う
+fn stable_hash(parts: &[&[u8]]) -> u64 {
+    let mut hash: u64 = 0xcbf29ce484222325;
+    for part in parts {
+        for byte in *part {
+            hash ^= u64::from(*byte);
+            hash = hash.wrapping_mul(0x100000001b3);
+        }
+        // 区切り（"ab" + "c" と "a" + "bc" を別にする）
+        hash ^= 0xff;
+        hash = hash.wrapping_mul(0x100000001b3);
+    }
+    hash
+}
+
+fn thumbnail_cache_path(path: &Path, max_edge: u32) -> Option<PathBuf> {
+    let modified = fs::metadata(path).and_then(|m| m.modified()).ok()?;
+    let modified = modified.duration_since(SystemTime::UNIX_EPOCH).ok()?;
+    let hash = stable_hash(&[
+        path.to_string_lossy().as_bytes(),
+        &modified.as_secs().to_le_bytes(),
+        &modified.subsec_nanos().to_le_bytes(),
+        &max_edge.to_le_bytes(),
+    ]);
+    Some(thumbnail_dir()?.join(format!("{:016x}.jpg", hash)))
+}
+
+// 古いサムネイルを消す。元の画像が変わると別の名前で作り直すので、使われなくなったものが溜まっていく
+fn prune_thumbnail_cache() {
+    let Some(dir) = thumbnail_dir() else {
+        return;
+    };
+    let Ok(entries) = fs::read_dir(&dir) else {
+        return;
+    };
+    let now = SystemTime::now();
+    let mut kept: Vec<(SystemTime, PathBuf)> = Vec::new();
+    for entry in entries.flatten() {
+        let path = entry.path();
+        let modified = entry
+            .metadata()
+            .and_then(|m| m.modified())
+            .unwrap_or(SystemTime::UNIX_EPOCH);
+        let expired = now
+            .duration_since(modified)
+            .is_ok_and(|age| age > THUMBNAIL_CACHE_MAX_AGE);
+        if expired {
+            let _ = fs::remove_file(&path);
+        } else {
+            kept.push((modified, path));
+        }
+    }
+    // 多すぎるときは古いものから消す
+    if kept.len() > THUMBNAIL_CACHE_LIMIT {
+        kept.sort();
+        let overflow = kept.len() - THUMBNAIL_CACHE_LIMIT;
+        for (_, path) in kept.drain(..overflow) {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn thumbnail_data_url(path: &Path, max_edge: u32) -> Result<String, String> {
+    let cache_path = thumbnail_cache_path(path, max_edge);
+    let cached = cache_path.as_ref().and_then(|p| fs::read(p).ok());
+    let jpeg = match cached {
+        Some(jpeg) => jpeg,
+        None => {
+            let jpeg = thumbnail_jpeg(path, max_edge)?;
+            if let Some(cache_path) = &cache_path {
+                // キャッシュに書けなくてもサムネイル自体は返す
+                let written = cache_path
+                    .parent()
+                    .map_or(Ok(()), fs::create_dir_all)
+                    .and_then(|_| fs::write(cache_path, &jpeg));
+                if let Err(e) = written {
+                    warn!("failed to cache thumbnail: {e}");
+                }
+            }
+            jpeg
+        }
+    };
+
+    Ok(format!("data:image/jpeg;base64,{}", BASE64.encode(jpeg)))
+}
+
+#[tauri::command]
+async fn get_thumbnail(path: String, max_edge: u32) -> Result<String, String> {
+    tauri::async_runtime::spawn_blocking(move || thumbnail_data_url(Path::new(&path), max_edge))
+        .await
+        .map_err(|e| e.to_string())?
+}
+
+// 画像の幅と高さ（ヘッダーだけ読む）。更新日時が同じならキャッシュを返す
+#[tauri::command]
+fn get_image_dimensions(app_handle: tauri::AppHandle, path: String) -> Result<(u32, u32), String> {
+    let path = PathBuf::from(path);
+    let modified = fs::metadata(&path)
+        .and_then(|m| m.modified())
+        .map_err(|e| format!("read error: {}", e))?;
+
+    let state = app_handle.state::<AppState>();
+    if let Some((_, dims)) = state
+        .dimension_cache
+        .lock()
+        .unwrap()
+        .get(&path)
+        .filter(|(at, _)| *at == modified)
+    {
+        return Ok(*dims);
+    }
+
+    let dims = image::image_dimensions(&path).map_err(|e| format!("decode error: {}", e))?;
+    let mut cache = state.dimension_cache.lock().unwrap();
+    if cache.len() >= DIMENSION_CACHE_LIMIT && !cache.contains_key(&path) {
+        cache.clear();
+    }
+    cache.insert(path, (modified, dims));
+    Ok(dims)
+}
+
+// 壁紙は変えずにライブラリを閲覧するためのプレビュー（current_index には触らない）
+#[tauri::command]
+async fn preview_at(
+    app_handle: tauri::AppHandle,
+    index: usize,
+    max_edge: u32,
+) -> Result<PreviewItem, String> {
+    // set_current_index と同じ番号になるよう、フォルダー・プレイリスト・タグを反映した対象を使う
+    let targets = effective_targets(&app_handle.state::<AppState>());
+
+    let total = targets.len();
+    let path = targets
+        .get(index)
+        .cloned()
+        .ok_or_else(|| format!("index out of range: {} (total {})", index, total))?;
+
+    let thumb_path = path.clone();
+    let data_url =
+        tauri::async_runtime::spawn_blocking(move || thumbnail_data_url(&thumb_path, max_edge))
+            .await
+            .map_err(|e| e.to_string())??;
+
+    Ok(PreviewItem {
+        index,
+        path: path.to_string_lossy().to_string(),
+        data_url,
+        prev_index: (index + total - 1) % total,
+        next_index: (index + 1) % total,
+        total,
+    })
+}
+
+fn manual_step(app_handle: &tauri::AppHandle, forward: bool) -> Result<String, String> {
+    let state = app_handle.state::<AppState>();
+    let targets = effective_targets(&state);
+    let random = effective_random(&state.config.lock().unwrap());
+
+    let (shown, outcomes) = step_wallpaper(&OsWallpaperSetter, &state, &targets, random, forward);
+    emit_outcomes(app_handle, &outcomes);
+    let path = shown.ok_or("no wallpaper to show")?;
+    // 手動で切り替えた場合も終了・範囲外で元の壁紙に戻す。ただし今が時間帯の外なら、
+    // 次の確認ですぐ戻されてしまうので動作中にはしない（終了時の復元は restore_on_quit に従う）
+    if in_schedule(&state) {
+        state.rotation.lock().unwrap().random_active = true;
+    }
+    update_tray(app_handle);
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+// 次の切替までの秒数（停止中・手動モードなど予定が無ければ None）
+#[tauri::command]
+fn get_next_change(app_handle: tauri::AppHandle) -> Option<u64> {
+    let state = app_handle.state::<AppState>();
+    let next = *state.next_change_at.lock().unwrap();
+    remaining_secs(next)
+}
+
+#[tauri::command]
+fn get_last_error(app_handle: tauri::AppHandle) -> Option<String> {
+    let state = app_handle.state::<AppState>();
+    let last_error = state.last_error.lock().unwrap();
+    last_error.clone()
+}
+
+// ロックは1つずつ取って離す（ループ側とロック順が食い違わないように）
+#[tauri::command]
+fn get_rotation_status(app_handle: tauri::AppHandle) -> RotationStatus {
+    let state = app_handle.state::<AppState>();
+    let targets = effective_targets(&state);
+    let (random, paused) = {
+        let cfg = state.config.lock().unwrap();
+        (effective_random(&cfg), cfg.paused)
+    };
+    let current = state.rotation.lock().unwrap().last_shown.clone();
+    let snooze_until = *state.snooze_until.lock().unwrap();
+
+    RotationStatus {
+        total: targets.len(),
+        current_index: current
+            .as_ref()
+            .and_then(|c| targets.iter().position(|p| p == c)),
+        current_path: current.map(|p| p.to_string_lossy().to_string()),
+        random,
+        paused,
+        snooze_remaining_secs: remaining_secs(snooze_until).filter(|secs| *secs > 0),
+    }
+}
+
+#[tauri::command]
+fn next_wallpaper(app_handle: tauri::AppHandle) -> Result<String, String> {
+    manual_step(&app_handle, true)
+}
+
+#[tauri::command]
+fn prev_wallpaper(app_handle: tauri::AppHandle) -> Result<String, String> {
+    manual_step(&app_handle, false)
+}
+
+// 指定した番号の画像へ移動する。自動の順番切替はその次から続く
+#[tauri::command]
+fn set_current_index(app_handle: tauri::AppHandle, index: usize) -> Result<String, String> {
+    let state = app_handle.state::<AppState>();
+    let targets = effective_targets(&state);
+    let path = targets
+        .get(index)
+        .cloned()
+        .ok_or_else(|| format!("index out of range: {} (len {})", index, targets.len()))?;
+
+    // next / prev と同じく、時間帯の外で移動した場合は動作中の扱いにしない
+    let in_schedule = in_schedule(&state);
+    {
+        let _stepping = state.step_lock.lock().unwrap();
+        let showing = showing_wallpaper(&OsWallpaperSetter, &state);
+        let outcome = apply_wallpaper(
+            &OsWallpaperSetter,
+            &state,
+            &path,
+            showing.as_deref(),
+            Some(index),
+            false,
+            "sequential",
+        );
+        let result = outcome.as_ref().map(|_| ()).map_err(|e| e.message.clone());
+        emit_outcomes(&app_handle, &[outcome]);
+        result?;
+        let mut rotation = state.rotation.lock().unwrap();
+        rotation.current_index = Some((index + 1) % targets.len());
+        rotation.last_shown = Some(path.clone());
+        if in_schedule {
+            rotation.random_active = true;
+        }
+    }
+    update_tray(&app_handle);
+
+    Ok(path.to_string_lossy().to_string())
+}
+
+// サムネイル一覧などから選んだ画像をすぐに設定する。切替対象に含まれていれば
+// 順番切替はその次から続く（対象外の画像も設定できる）
+#[tauri::command]
+fn set_specific_wallpaper(app_handle: tauri::AppHandle, path: String) -> Result<(), String> {
+    let path = PathBuf::from(path);
+    if !path.is_file() {
+        return Err(format!("file not found: {}", path.display()));
+    }
+
+    let state = app_handle.state::<AppState>();
+    let targets = effective_targets(&state);
+    let position = targets.iter().position(|p| *p == path);
+    let (random, direction) = {
+        let cfg = state.config.lock().unwrap();
+        (
+            effective_random(&cfg),
+            cfg.sequential_direction
+                .clone()
+                .unwrap_or_else(|| "forward".to_string()),
+        )
+    };
+
+    // 時間帯の外で選んだ画像はループにすぐ戻されないよう、動作中の扱いにしない
+    let in_schedule = in_schedule(&state);
+    {
+        // step_wallpaper と同じく、設定中は選択位置のロックを持たない
+        let _stepping = state.step_lock.lock().unwrap();
+        let showing = showing_wallpaper(&OsWallpaperSetter, &state);
+        let outcome = apply_wallpaper(
+            &OsWallpaperSetter,
+            &state,
+            &path,
+            showing.as_deref(),
+            position,
+            random,
+            "manual",
+        );
+        let result = outcome.as_ref().map(|_| ()).map_err(|e| e.message.clone());
+        emit_outcomes(&app_handle, &[outcome]);
+        result?;
+        let mut rotation = state.rotation.lock().unwrap();
+        if let Some(index) = position.filter(|_| !random) {
+            rotation.current_index = Some(sequential_step(
+                index,
+                targets.len(),
+                &direction,
+                &mut rotation.pingpong_reverse,
+                false,
+            ));
+        }
+        rotation.last_shown = Some(path);
+        if in_schedule {
+            rotation.random_active = true;
+        }
+    }
+    update_tray(&app_handle);
+
+    Ok(())
+}
+
+// 次に表示する予定の画像を返す（壁紙・位置は変えない）。
+// 順番切替は実際の次と一致する。ランダムは乱数の状態を複製して同じ選び方をするだけなので、
+// それまでに対象や直近の表示が変われば実際の選択とは異なる（目安として使う）
+#[tauri::command]
+fn peek_next(app_handle: tauri::AppHandle) -> Option<String> {
+    let state = app_handle.state::<AppState>();
+    let targets = effective_targets(&state);
+    if targets.is_empty() {
+        return None;
+    }
+    let len = targets.len();
+    let (random, direction, group_by_folder, folder_weighted, weights, daily_mode, seed) = {
+        let cfg = state.config.lock().unwrap();
+        (
+            effective_random(&cfg),
+            cfg.sequential_direction
+                .clone()
+                .unwrap_or_else(|| "forward".to_string()),
+            cfg.group_by_folder,
+            cfg.folder_weighted,
+            cfg.weights.clone(),
+            cfg.daily_mode,
+            cfg.random_seed,
+        )
+    };
+
+    let next = if daily_mode {
+        // 日替わりは翌日の画像
+        let tomorrow = Local::now().date_naive().succ_opt()?;
+        daily_pick(&targets, tomorrow, random, seed)?.clone()
+    } else if group_by_folder {
+        let groups = group_by_parent(&targets);
+        let cursor = state.rotation.lock().unwrap().folder_cursor.clone();
+        let g = cursor
+            .folder
+            .as_ref()
+            .and_then(|f| groups.iter().position(|(d, _)| d == f))
+            .map_or(0, |pos| (pos + 1) % groups.len());
+        let (dir, members) = &groups[g];
+        let index = if random {
+            let mut shuffled = members.clone();
+            shuffled.shuffle(&mut state.rng.lock().unwrap().clone());
+            shuffled[0]
+        } else {
+            members[cursor.positions.get(dir).copied().unwrap_or(0) % members.len()]
+        };
+        targets[index].clone()
+    } else if random {
+        let recent = state.recent_paths.lock().unwrap();
+        let mut rng = state.rng.lock().unwrap().clone();
+        let index = pick_random(&targets, &[], &recent, &weights, folder_weighted, &mut rng)?;
+        targets[index].clone()
+    } else {
+        // step_wallpaper と同じく、ランダムから切り替えた直後は表示中の画像の次から
+        let RotationState {
+            last_random_enabled: last_rand,
+            current_index: idx,
+            last_shown,
+            pingpong_reverse: mut reverse,
+            ..
+        } = state.rotation.lock().unwrap().clone();
+        let index = match idx {
+            None if last_rand => last_shown
+                .or_else(|| OsWallpaperSetter.get())
+                .and_then(|c| targets.iter().position(|p| *p == c))
+                .map(|pos| sequential_step(pos, len, &direction, &mut reverse, false))
+                .unwrap_or(0),
+            _ => idx.unwrap_or(0) % len,
+        };
+        targets[index].clone()
+    };
+
+    Some(next.to_string_lossy().to_string())
+}
+
+// 表示中の壁紙を、実行時間帯の外で戻す壁紙（default_wallpaper_path）にする
+#[tauri::command]
+fn set_current_as_default(app_handle: tauri::AppHandle) -> Result<String, String> {
+    let state = app_handle.state::<AppState>();
+    let current = state
+        .rotation
+        .lock()
+        .unwrap()
+        .last_shown
+        .clone()
+        .ok_or("no current wallpaper")?;
+    update_config(&app_handle.state::<AppState>(), |cfg| {
+        cfg.default_wallpaper_path = Some(current.clone());
+        Ok(())
+    })?;
+
+    Ok(current.to_string_lossy().to_string())
+}
+
+// 表示中の壁紙のフルパスをクリップボードにコピーして返す
+#[tauri::command]
+fn copy_current_path(app_handle: tauri::AppHandle) -> Result<String, String> {
+    let state = app_handle.state::<AppState>();
+    let path = state
+        .rotation
+        .lock()
+        .unwrap()
+        .last_shown
+        .clone()
+        .ok_or("no current wallpaper")?;
+    let text = path.to_string_lossy().to_string();
+    app_handle
+        .clipboard()
+        .write_text(text.clone())
+        .map_err(|e| format!("clipboard error: {}", e))?;
+    Ok(text)
+}
+
+// 表示中の壁紙をファイルマネージャーで開く（Linux は親フォルダーを開くだけ）
+#[tauri::command]
+fn reveal_current_wallpaper(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let state = app_handle.state::<AppState>();
+    let path = state
+        .rotation
+        .lock()
+        .unwrap()
+        .last_shown
+        .clone()
+        .ok_or("no current wallpaper")?;
+    if !path.is_file() {
+        return Err(format!("file not found: {}", path.display()));
+    }
+
+    let mut command = if cfg!(target_os = "windows") {
+        // explorer は成功しても終了コード 1 を返すので結果は見ない
+        let mut c = std::process::Command::new("explorer");
+        c.arg("/select,").arg(&path);
+        c
+    } else if cfg!(target_os = "macos") {
+        let mut c = std::process::Command::new("open");
+        c.arg("-R").arg(&path);
+        c
+    } else {
+        let mut c = std::process::Command::new("xdg-open");
+        c.arg(path.parent().unwrap_or(Path::new("/")));
+        c
+    };
+    command
+        .spawn()
+        .map(|_| ())
+        .map_err(|e| format!("reveal error: {}", e))
+}
+
+const CLI_HELP: &str = "\
+Usage: wallpaper_changer [options]
+
+Options (override the config file for this run only):
+  --interval <n>        change interval (in interval_unit)
+  --random[=true|false] random order (sequential with --random=false)
+  --dir <path>          add the images in <path> to the targets (repeatable)
+  --start <HH:MM>       start of the run window
+  --end <HH:MM>         end of the run window
+  --headless            run without the window (tray and rotation only)
+  --save                also write the overrides to the config file
+  --help                show this help";
+
+// コマンドライン引数による一時的な設定の上書き
+#[derive(Default)]
+struct CliOverrides {
+    interval: Option<u64>,
+    random: Option<bool>,
+    dirs: Vec<String>,
+    start: Option<String>,
+    end: Option<String>,
+    headless: bool,
+    save: bool,
+}
+
+// --flag value / --flag=value の両方を受け付ける。--help は Ok(None)
+fn parse_cli_args(mut args: impl Iterator<Item = String>) -> Result<Option<CliOverrides>, String> {
+    let mut overrides = CliOverrides::default();
+    while let Some(arg) = args.next() {
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_string(), Some(value.to_string())),
+            None => (arg, None),
+        };
+        let mut value = |name: &str| {
+            inline
+                .clone()
+                .or_else(|| args.next())
+                .ok_or_else(|| format!("missing value for {}", name))
+        };
+        match flag.as_str() {
+            "--help" | "-h" => return Ok(None),
+            "--interval" => {
+                let v = value("--interval")?;
+                overrides.interval =
+                    Some(v.parse().map_err(|_| format!("invalid interval: {}", v))?);
+            }
+            "--random" => {
+                overrides.random = Some(match inline.as_deref() {
+                    None | Some("true") => true,
+                    Some("false") => false,
+                    Some(v) => return Err(format!("invalid value for --random: {}", v)),
+                });
+            }
+            "--dir" => overrides.dirs.push(value("--dir")?),
+            "--start" | "--end" => {
+                let v = value(&flag)?;
+                if parse_hhmm(&v).is_none() {
+                    return Err(format!("invalid time for {}: {}", flag, v));
+                }
+                if flag == "--start" {
+                    overrides.start = Some(v);
+                } else {
+                    overrides.end = Some(v);
+                }
+            }
+            "--headless" => overrides.headless = true,
+            "--save" => overrides.save = true,
+            _ => return Err(format!("unknown argument: {}", flag)),
+        }
+    }
+    Ok(Some(overrides))
+}
+
+// windows_subsystem = "windows" では標準出力がどこにもつながらないので、起動元のコンソールにつなぐ
+#[cfg(target_os = "windows")]
+fn attach_console() {
+    use windows::Win32::System::Console::{AttachConsole, ATTACH_PARENT_PROCESS};
+
+    // コンソールから起動されていなければ失敗するが、そのときは表示先がないだけなので無視する
+    let _ = unsafe { AttachConsole(ATTACH_PARENT_PROCESS) };
+}
+
+#[cfg(not(target_os = "windows"))]
+fn attach_console() {}
+
+fn apply_cli_overrides(cfg: &mut AppConfig, overrides: CliOverrides) {
+    if let Some(interval) = overrides.interval {
+        cfg.interval = interval;
+    }
+    if let Some(random) = overrides.random {
+        cfg.random = random;
+    }
+    if overrides.start.is_some() {
+        cfg.start_dt = overrides.start;
+    }
+    if overrides.end.is_some() {
+        cfg.end_dt = overrides.end;
+    }
+    if overrides.headless {
+        cfg.headless = true;
+    }
+    let scan = ScanOptions::from_config(cfg);
+    append_images(&mut cfg.file_targets, overrides.dirs, scan);
+}
+
+// --save なしで上書きしたときの (上書き前, 上書き後) の設定。保存時は上書き分を元に戻す
+static CLI_CONFIGS: OnceLock<(AppConfig, AppConfig)> = OnceLock::new();
+
+// 設定ファイルに書く内容。コマンドラインで上書きしたままの項目は上書き前の値に戻す
+// （実行中に UI などで変えた項目はそのまま保存する）
+fn persisted_config(cfg: &AppConfig) -> AppConfig {
+    let mut out = cfg.clone();
+    if let Some((before, after)) = CLI_CONFIGS.get() {
+        if out.interval == after.interval {
+            out.interval = before.interval;
+        }
+        if out.random == after.random {
+            out.random = before.random;
+        }
+        if out.start_dt == after.start_dt {
+            out.start_dt = before.start_dt.clone();
+        }
+        if out.end_dt == after.end_dt {
+            out.end_dt = before.end_dt.clone();
+        }
+        if out.headless == after.headless {
+            out.headless = before.headless;
+        }
+        if out.file_targets == after.file_targets {
+            out.file_targets = before.file_targets.clone();
+        }
+    }
+    out
+}
+
+// 設定ファイルが外から書き換えられて読み直すときも、コマンドラインの上書きは残す
+fn reapply_cli_overrides(cfg: &mut AppConfig) {
+    let Some((before, after)) = CLI_CONFIGS.get() else {
+        return;
+    };
+    if before.interval != after.interval {
+        cfg.interval = after.interval;
+    }
+    if before.random != after.random {
+        cfg.random = after.random;
+    }
+    if before.start_dt != after.start_dt {
+        cfg.start_dt = after.start_dt.clone();
+    }
+    if before.end_dt != after.end_dt {
+        cfg.end_dt = after.end_dt.clone();
+    }
+    if before.headless != after.headless {
+        cfg.headless = after.headless;
+    }
+    for target in &after.file_targets {
+        if !cfg.file_targets.contains(target) {
+            cfg.file_targets.push(target.clone());
+        }
+    }
+}
+
+// 設定フォルダーを OS のファイルマネージャーで開く
+#[tauri::command]
+fn open_config_dir() -> Result<(), String> {
+    let dir = config_dir()?;
+    let program = if cfg!(target_os = "windows") {
+        "explorer"
+    } else if cfg!(target_os = "macos") {
+        "open"
+    } else {
+        "xdg-open"
+    };
+    std::process::Command::new(program)
+        .arg(&dir)
+        .spawn()
+        .map(|_| ())
+        .map_err(|e| format!("open error: {}", e))
+}
+
+// 今の壁紙が最後に表示した画像と違い、切替対象でもなければユーザーが自分で変えたものとして
+// 戻し先（initial_wallpaper）をそれに置き換える。respect_external_changes のときだけ
+fn adopt_external_wallpaper(state: &AppState) {
+    if !state.config.lock().unwrap().respect_external_changes {
+        return;
+    }
+    let Some(current) = get_current_wallpaper() else {
+        return;
+    };
+    let last_shown = state.rotation.lock().unwrap().last_shown.clone();
+    // 変換して設定した画像は一時フォルダーのコピーが壁紙になっている
+    if last_shown.as_ref() == Some(&current)
+        || current.starts_with(transcode_dir())
+        || effective_targets(state).contains(&current)
+    {
+        return;
+    }
+
+    let mut initial = state.initial_wallpaper.lock().unwrap();
+    if initial.as_ref() == Some(&current) {
+        return;
+    }
+    info!(
+        "wallpaper changed externally; keeping {}",
+        current.display()
+    );
+    *initial = Some(current.clone());
+    drop(initial);
+    state.config.lock().unwrap().initial_wallpaper = Some(current);
+    mark_config_dirty(state);
+}
+
+// 時間帯の外などで戻す壁紙（default_wallpaper_path があればそちら、無ければ元の壁紙）
+fn restore_target(state: &AppState) -> Option<PathBuf> {
+    let default_wallpaper = state.config.lock().unwrap().default_wallpaper_path.clone();
+    default_wallpaper.filter(|p| p.is_file()).or_else(|| {
+        // 消された画像には戻せないので、その場合は fallback_color に任せる
+        let initial = state.initial_wallpaper.lock().unwrap().clone();
+        initial.filter(|p| p.is_file())
+    })
+}
+
+// "#rrggbb" / "#rgb" を RGB に変換する（# は省略可）
+fn parse_hex_color(s: &str) -> Option<[u8; 3]> {
+    let hex = s.trim().trim_start_matches('#');
+    if !hex.is_ascii() {
+        return None;
+    }
+    let channel = |i: usize, len: usize| {
+        let v = u8::from_str_radix(&hex[i * len..(i + 1) * len], 16).ok()?;
+        Some(if len == 1 { v * 17 } else { v })
+    };
+    let len = match hex.len() {
+        3 => 1,
+        6 => 2,
+        _ => return None,
+    };
+    Some([channel(0, len)?, channel(1, len)?, channel(2, len)?])
+}
+
+// 単色の画像を一時フォルダーに書き出す。壁紙 API は画像しか受け付けないので、
+// 小さい画像を引き伸ばして表示させる
+fn solid_color_image(rgb: [u8; 3]) -> Result<PathBuf, String> {
+    let dir = transcode_dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("write error: {}", e))?;
+    let out = dir.join(format!(
+        "solid_{:02x}{:02x}{:02x}.png",
+        rgb[0], rgb[1], rgb[2]
+    ));
+    image::RgbImage::from_pixel(16, 16, image::Rgb(rgb))
+        .save_with_format(&out, image::ImageFormat::Png)
+        .map_err(|e| format!("encode error: {}", e))?;
+    Ok(out)
+}
+
+// 戻す壁紙も切替対象も無いときに表示する単色（fallback_color が無ければ何もしない）
+fn fallback_wallpaper(state: &AppState) -> Option<PathBuf> {
+    let color = state.config.lock().unwrap().fallback_color.clone()?;
+    let rgb = parse_hex_color(&color)?;
+    solid_color_image(rgb)
+        .map_err(|e| error!("failed to prepare fallback color {color}: {e}"))
+        .ok()
+}
+
+// ループから元の壁紙に戻すとき用。今の壁紙の確認と設定は時間がかかるので spawn_blocking で行う
+async fn restore_blocking(app_handle: &tauri::AppHandle) {
+    let handle = app_handle.clone();
+    let restored = tauri::async_runtime::spawn_blocking(move || {
+        let state = handle.state::<AppState>();
+        adopt_external_wallpaper(&state);
+        if let Some(path) = restore_target(&state).or_else(|| fallback_wallpaper(&state)) {
+            apply_and_emit(&handle, &path, None, false, "restore");
+        }
+    })
+    .await;
+    if let Err(e) = restored {
+        error!("wallpaper restore failed: {e}");
+    }
+}
+
+// 終了時の壁紙の処理。restore_on_quit が false なら今の壁紙のまま終了する。
+// true なら exit_wallpaper_path があればそれを設定し、無ければ元の壁紙に戻す
+fn restore_on_exit(app_handle: &tauri::AppHandle) {
+    let state = app_handle.state::<AppState>();
+    // 終了処理は複数の経路から呼ばれるので最初の 1 回だけ行う
+    if state.exit_handled.swap(true, Ordering::SeqCst) {
+        return;
+    }
+    let (restore_on_quit, exit_wallpaper, dry_run) = {
+        let cfg = state.config.lock().unwrap();
+        (
+            cfg.restore_on_quit,
+            cfg.exit_wallpaper_path.clone(),
+            cfg.dry_run,
         )
+    };
+    // dry_run 中は壁紙を変えていないので戻さない
+    if dry_run || !restore_on_quit {
+        return;
+    }
+
+    if let Some(path) = exit_wallpaper {
+        if path.is_file() {
+            let _ = set_wallpaper(&path);
+            return;
+        }
+        warn!(
+            "exit wallpaper not found: {}; falling back to restore",
+            path.display()
+        );
+    }
+
+    adopt_external_wallpaper(&state);
+    let initial = state.initial_wallpaper.lock().unwrap().clone();
+    if let Some(path) = initial
+        .filter(|p| p.is_file())
+        .or_else(|| fallback_wallpaper(&state))
+    {
+        let _ = set_wallpaper(&path);
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum HotkeyAction {
+    Next,
+    Prev,
+    TogglePause,
+}
+
+fn run_hotkey_action(app_handle: &tauri::AppHandle, action: HotkeyAction) {
+    let result = match action {
+        HotkeyAction::Next => manual_step(app_handle, true).map(|_| ()),
+        HotkeyAction::Prev => manual_step(app_handle, false).map(|_| ()),
+        HotkeyAction::TogglePause => {
+            let paused = app_handle.state::<AppState>().config.lock().unwrap().paused;
+            set_paused(app_handle, !paused)
+        }
+    };
+    if let Err(e) = result {
+        warn!("hotkey {:?} failed: {e}", action);
+    }
+}
+
+// メインウィンドウを復元して前面に出す（トレイの「表示」と二重起動時に使う）
+fn show_main_window(app_handle: &tauri::AppHandle) {
+    if let Some(window) = app_handle.get_webview_window("wallpaper_changer") {
+        let _ = window.unminimize();
+        let _ = window.show();
+        let _ = window.set_focus();
+    }
+}
+
+fn emit_hotkey_error(app_handle: &tauri::AppHandle, combo: &str, message: String) {
+    warn!("failed to register hotkey {combo}: {message}");
+    let _ = app_handle.emit(
+        "hotkey-error",
+        HotkeyError {
+            shortcut: combo.to_string(),
+            message,
+        },
+    );
+}
+
+// グローバルホットキーを登録し直す（古い登録は先に外す）。
+// 不正な組み合わせや重複は hotkey-error を通知してその1件だけ飛ばす
+fn register_hotkeys(app_handle: &tauri::AppHandle, cfg: &AppConfig) {
+    let shortcuts = app_handle.global_shortcut();
+    if let Err(e) = shortcuts.unregister_all() {
+        warn!("failed to unregister hotkeys: {e}");
+    }
+
+    let bindings = [
+        (cfg.hotkey_next.as_deref(), HotkeyAction::Next),
+        (cfg.hotkey_prev.as_deref(), HotkeyAction::Prev),
+        (
+            cfg.hotkey_toggle_pause.as_deref(),
+            HotkeyAction::TogglePause,
+        ),
+    ];
+
+    let mut bound: Vec<String> = Vec::new();
+    for (combo, action) in bindings {
+        let Some(combo) = combo.map(str::trim).filter(|c| !c.is_empty()) else {
+            continue;
+        };
+        let key = combo.to_ascii_lowercase();
+        if bound.contains(&key) {
+            emit_hotkey_error(
+                app_handle,
+                combo,
+                format!("duplicate hotkey for {:?}", action),
+            );
+            continue;
+        }
+
+        let registered = shortcuts.on_shortcut(combo, move |app, _shortcut, event| {
+            if event.state == ShortcutState::Pressed {
+                run_hotkey_action(app, action);
+            }
+        });
+        match registered {
+            Ok(()) => bound.push(key),
+            Err(e) => emit_hotkey_error(app_handle, combo, e.to_string()),
+        }
+    }
+}
+
+// 保存・読み込み時に弾く設定の誤り（全て集めて返す）
+fn config_errors(config: &AppConfig) -> Vec<String> {
+    let mut errors = Vec::new();
+    if log_level_filter(config.log_level.as_deref()).is_none() {
+        errors.push(format!(
+            "unknown log level: {}",
+            config.log_level.as_deref().unwrap_or_default()
+        ));
+    }
+    if let Some(format) = &config.config_format {
+        if !format.eq_ignore_ascii_case("json") && !format.eq_ignore_ascii_case("toml") {
+            errors.push(format!("unknown config format: {}", format));
+        }
+    }
+    if let Some(collections) = &config.time_collections {
+        for slot in collections.keys() {
+            if !matches!(slot.as_str(), "morning" | "afternoon" | "evening" | "night") {
+                errors.push(format!("unknown time slot: {}", slot));
+            }
+        }
+    }
+    match (config.latitude, config.longitude) {
+        (Some(lat), Some(lon)) => {
+            if !(-90.0..=90.0).contains(&lat) || !(-180.0..=180.0).contains(&lon) {
+                errors.push(format!("invalid location: {}, {}", lat, lon));
+            }
+        }
+        (None, None) => {}
+        _ => errors.push("latitude and longitude must be set together".to_string()),
+    }
+    let hours = &config.time_slot_hours;
+    if !(hours.morning < hours.afternoon
+        && hours.afternoon < hours.evening
+        && hours.evening < hours.night
+        && hours.night < 24)
+    {
+        errors.push(format!(
+            "invalid time slot hours: {}/{}/{}/{}",
+            hours.morning, hours.afternoon, hours.evening, hours.night
+        ));
+    }
+    if let Some(color) = &config.fallback_color {
+        if parse_hex_color(color).is_none() {
+            errors.push(format!("invalid fallback color: {}", color));
+        }
+    }
+    for time in [&config.dnd_start, &config.dnd_end].into_iter().flatten() {
+        if parse_hhmm(time).is_none() {
+            errors.push(format!("invalid dnd time: {}", time));
+        }
+    }
+    // 片方だけでは DND が効かないので誤りとして扱う
+    if config.dnd_start.is_some() != config.dnd_end.is_some() {
+        errors.push("dnd_start and dnd_end must be set together".to_string());
+    }
+    if let Some(m) = config.battery_interval_multiplier {
+        if !m.is_finite() || m <= 0.0 {
+            errors.push(format!("invalid battery interval multiplier: {}", m));
+        }
+    }
+    if let Some(expr) = &config.cron {
+        if let Err(e) = Schedule::from_str(expr) {
+            errors.push(format!("invalid cron expression: {}", e));
+        }
+    }
+    if let Some(direction) = &config.sequential_direction {
+        if !matches!(direction.as_str(), "forward" | "reverse" | "pingpong") {
+            errors.push(format!("unknown sequential direction: {}", direction));
+        }
+    }
+    if interval_unit_secs(config.interval_unit.as_deref()).is_none() {
+        errors.push(format!(
+            "unknown interval unit: {}",
+            config.interval_unit.as_deref().unwrap_or_default()
+        ));
+    }
+    if let Some(weekly) = &config.weekly {
+        let invalid = invalid_weekdays(weekly);
+        if !invalid.is_empty() {
+            errors.push(format!("unknown weekday: {}", invalid.join(", ")));
+        }
+    }
+    if let Some(monthly) = &config.monthly {
+        let invalid: Vec<String> = monthly
+            .iter()
+            .filter(|d| !(1..=31).contains(*d))
+            .map(|d| d.to_string())
+            .collect();
+        if !invalid.is_empty() {
+            errors.push(format!("invalid monthly day: {}", invalid.join(", ")));
+        }
+    }
+    if let Some(patterns) = &config.monthly_patterns {
+        let invalid: Vec<String> = patterns
+            .iter()
+            .filter(|p| parse_month_pattern(p).is_none())
+            .cloned()
+            .collect();
+        if !invalid.is_empty() {
+            errors.push(format!("invalid monthly pattern: {}", invalid.join(", ")));
+        }
+    }
+    errors
+}
+
+fn check_config(config: &AppConfig) -> Result<(), String> {
+    let errors = config_errors(config);
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors.join("; "))
+    }
+}
+
+// 保存前に UI のフォームの問題を全て表示するための検証。config_errors に加えて
+// 開始・終了時刻の形式と、画像ファイルが見つかるかも調べる
+#[tauri::command]
+fn validate_config(config: AppConfig) -> Result<(), Vec<String>> {
+    let mut errors = config_errors(&config);
+    for (name, time) in [
+        ("start time", &config.start_dt),
+        ("end time", &config.end_dt),
+    ] {
+        if let Some(time) = time.as_deref().filter(|t| parse_hhmm(t).is_none()) {
+            errors.push(format!("invalid {}: {}", name, time));
+        }
+    }
+    if let Some(path) = config
+        .default_wallpaper_path
+        .as_ref()
+        .filter(|p| !p.is_file())
+    {
+        errors.push(format!("default wallpaper not found: {}", path.display()));
+    }
+    for path in config.file_targets.iter().filter(|p| !p.is_file()) {
+        errors.push(format!("file not found: {}", path.display()));
+    }
+    if errors.is_empty() {
+        Ok(())
+    } else {
+        Err(errors)
+    }
+}
+
+// アプリやそれぞれのコマンドが管理する項目（元の壁紙・一時停止・プレイリスト・タグ・重み・自動起動）。
+// save_config では受け取らず、送られてきても今の値を使う
+const COMMAND_MANAGED_FIELDS: [&str; 8] = [
+    "initialWallpaper",
+    "paused",
+    "playlists",
+    "activePlaylist",
+    "tags",
+    "activeTags",
+    "weights",
+    "startOnLogin",
+];
+
+// フロントから送られた設定を今の設定に重ねる。送られなかった項目と COMMAND_MANAGED_FIELDS は今の値のまま。
+// file_targets が空の場合、clear_targets が true なら空のまま保存し、
+// false なら「送られていない」とみなして保存済みの対象を引き継ぐ
+fn merge_config_form(
+    current: &AppConfig,
+    form: serde_json::Value,
+    clear_targets: bool,
+) -> Result<AppConfig, String> {
+    let serde_json::Value::Object(form) = form else {
+        return Err("parse error: config must be an object".to_string());
+    };
+    let mut value = serde_json::to_value(current).map_err(|e| format!("serialize error: {}", e))?;
+    if let Some(fields) = value.as_object_mut() {
+        for (key, field) in form {
+            if !COMMAND_MANAGED_FIELDS.contains(&key.as_str()) {
+                fields.insert(key, field);
+            }
+        }
+    }
+    let mut merged: AppConfig =
+        serde_json::from_value(value).map_err(|e| format!("parse error: {}", e))?;
+    if merged.file_targets.is_empty() && !clear_targets {
+        merged.file_targets = current.file_targets.clone();
+    }
+    Ok(merged)
+}
+
+#[tauri::command]
+fn save_config(
+    app_handle: tauri::AppHandle,
+    config: serde_json::Value,
+    clear_targets: Option<bool>,
+    validate: Option<bool>,
+) -> Result<(), String> {
+    let state = app_handle.state::<AppState>();
+    let current = state.config.lock().unwrap().clone();
+    let merged = merge_config_form(&current, config, clear_targets.unwrap_or(false))?;
+    check_config(&merged)?;
+
+    // validate: true のときは validate_config の問題が一つでもあれば保存しない
+    if validate.unwrap_or(false) {
+        validate_config(merged.clone()).map_err(|errors| errors.join("; "))?;
+    }
+
+    apply_config(&app_handle, merged);
+    mark_config_dirty(&state);
+    Ok(())
+}
+
+// 新しい設定を AppState に反映し、関連する状態（ホットキー・監視・乱数）を更新してループを起こす
+fn apply_config(app_handle: &tauri::AppHandle, merged: AppConfig) {
+    let state = app_handle.state::<AppState>();
+    invalidate_targets(&state);
+    if let Some(level) = log_level_filter(merged.log_level.as_deref()) {
+        log::set_max_level(level);
+    }
+    let (seed_changed, hotkeys_changed, folders_changed, interval_changed) = {
+        let mut cfg = state.config.lock().unwrap();
+        let changed = (
+            cfg.random_seed != merged.random_seed,
+            cfg.hotkey_next != merged.hotkey_next
+                || cfg.hotkey_prev != merged.hotkey_prev
+                || cfg.hotkey_toggle_pause != merged.hotkey_toggle_pause,
+            cfg.folder_targets != merged.folder_targets,
+            effective_interval_secs(&cfg) != effective_interval_secs(&merged)
+                || cfg.interval_jitter_secs != merged.interval_jitter_secs,
+        );
+        *cfg = merged.clone();
+        changed
+    };
+    if hotkeys_changed {
+        register_hotkeys(app_handle, &merged);
+    }
+    if folders_changed {
+        watch_folder_targets(app_handle, &merged.folder_targets);
+    }
+    if seed_changed {
+        // restart the sequence so the new seed reproduces from its first pick
+        *state.rng.lock().unwrap() = make_rng(merged.random_seed);
+    }
+    // also update the remembered last_random_enabled so the main loop can detect toggles
+    state.rotation.lock().unwrap().last_random_enabled = effective_random(&merged);
+
+    // 設定の変更では壁紙は変えず、間隔が変わったときだけ次の切替時刻を計算し直す
+    if interval_changed {
+        request_reschedule(&state);
+    } else {
+        state.notify.notify_one();
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<AppConfig, String> {
+    let content = fs::read_to_string(path).map_err(|e| format!("read error: {}", e))?;
+    let cfg = parse_config(path, &content)?;
+    check_config(&cfg)?;
+    Ok(cfg)
+}
+
+// 設定ファイルを読み直して反映する。読めない・不正な場合は今の設定のまま
+#[tauri::command]
+fn reload_config(app_handle: tauri::AppHandle) -> Result<AppConfig, String> {
+    let exe_path = std::env::current_exe().map_err(|e| e.to_string())?;
+    let exe_dir = exe_path.parent().ok_or("failed to get exe dir")?;
+    let cfg = read_config_file(&config_path(exe_dir))?;
+    apply_config(&app_handle, cfg.clone());
+    Ok(cfg)
+}
+
+// 別の設定ファイルを読み込んで今の設定を置き換える。不正なら今の設定のまま。
+// 元の壁紙はこの PC のものを残す。見つからないパスは警告として config-import-warnings で通知する
+#[tauri::command]
+fn import_config(app_handle: tauri::AppHandle, src: String) -> Result<AppConfig, String> {
+    let mut cfg = read_config_file(Path::new(&src))?;
+    for (name, time) in [("startDt", &cfg.start_dt), ("endDt", &cfg.end_dt)] {
+        if let Some(time) = time.as_deref().filter(|t| parse_hhmm(t).is_none()) {
+            return Err(format!("invalid {}: {}", name, time));
+        }
+    }
+
+    let state = app_handle.state::<AppState>();
+    cfg.initial_wallpaper = state.config.lock().unwrap().initial_wallpaper.clone();
+
+    let missing: Vec<String> = cfg
+        .file_targets
+        .iter()
+        .chain(&cfg.folder_targets)
+        .chain(cfg.playlists.values().flat_map(|p| &p.targets))
+        .chain(&cfg.default_wallpaper_path)
+        .chain(&cfg.exit_wallpaper_path)
+        .filter(|p| !p.exists())
+        .map(|p| format!("not found: {}", p.display()))
+        .collect();
+    if !missing.is_empty() {
+        for message in &missing {
+            warn!("import_config: {message}");
+        }
+        let _ = app_handle.emit("config-import-warnings", &missing);
+    }
+
+    sync_autostart(&app_handle, cfg.start_on_login)?;
+    apply_config(&app_handle, cfg.clone());
+    mark_config_dirty(&state);
+    Ok(cfg)
+}
+
+// 設定ファイルを手で書き換えたときに再起動せずに反映する。
+// アプリ自身の書き込みは内容がメモリ上の設定と同じになるので無視される
+fn watch_config_file(app_handle: &tauri::AppHandle) {
+    // 一時ファイルからの rename で置き換えるのでファイルではなくフォルダーを監視する
+    let Ok(dir) = config_dir() else {
+        return;
+    };
+
+    let handle = app_handle.clone();
+    let watched_dir = dir.clone();
+    let debouncer = new_debouncer(FOLDER_WATCH_DEBOUNCE, move |res: DebounceEventResult| {
+        let events = match res {
+            Ok(events) => events,
+            Err(e) => {
+                warn!("config watch error: {e}");
+                return;
+            }
+        };
+        // config.json と config.toml のどちらが変わっても今使っている方を読み直す
+        let config_path = config_path(&watched_dir);
+        if !events.iter().any(|e| {
+            matches!(
+                e.path.file_name().and_then(|n| n.to_str()),
+                Some("config.json" | "config.toml")
+            )
+        }) {
+            return;
+        }
+        if !config_path.exists() {
+            return;
+        }
+        // 書き出し待ちの変更があればメモリの方が新しい（すぐ上書きされる）ので読み直さない
+        let state = handle.state::<AppState>();
+        if state.config_dirty.load(Ordering::SeqCst) {
+            return;
+        }
+
+        let mut cfg = match read_config_file(&config_path) {
+            Ok(cfg) => cfg,
+            Err(e) => {
+                warn!("ignoring invalid {}: {e}", config_path.display());
+                return;
+            }
+        };
+        let unchanged = persisted_config(&state.config.lock().unwrap()) == cfg;
+        if !unchanged {
+            reapply_cli_overrides(&mut cfg);
+            apply_config(&handle, cfg);
+        }
+    });
+    let mut debouncer = match debouncer {
+        Ok(debouncer) => debouncer,
+        Err(e) => {
+            warn!("failed to start config watcher: {e}");
+            return;
+        }
+    };
+    if let Err(e) = debouncer.watcher().watch(&dir, RecursiveMode::NonRecursive) {
+        warn!("failed to watch {}: {e}", dir.display());
+        return;
+    }
+    *app_handle
+        .state::<AppState>()
+        .config_watcher
+        .lock()
+        .unwrap() = Some(debouncer);
+}
+
+// 設定ファイル・ログ・履歴などを置くディレクトリ（実行ファイルと同じ場所）
+fn config_dir() -> Result<PathBuf, String> {
+    let exe_path = std::env::current_exe().map_err(|e| e.to_string())?;
+    let exe_dir = exe_path.parent().ok_or("failed to get exe dir")?;
+    Ok(exe_dir.to_path_buf())
+}
+
+fn write_config(cfg: &AppConfig) -> Result<(), String> {
+    write_config_file(&config_dir()?, &persisted_config(cfg))
+}
+
+// 設定はメモリ上の AppState.config が正。変更したらこれで印を付け、
+// CONFIG_FLUSH_INTERVAL ごとと終了時に flush_config でまとめて書き出す
+fn mark_config_dirty(state: &AppState) {
+    state.config_dirty.store(true, Ordering::SeqCst);
+    invalidate_targets(state);
+}
+
+// 変更があったときだけ設定ファイルに書き出す。失敗したら印を戻して次の機会に書き直す
+fn flush_config(state: &AppState) -> Result<(), String> {
+    let _flushing = state.config_flush.lock().unwrap();
+    if !state.config_dirty.swap(false, Ordering::SeqCst) {
+        return Ok(());
+    }
+    let cfg = state.config.lock().unwrap().clone();
+    write_config(&cfg).inspect_err(|_| state.config_dirty.store(true, Ordering::SeqCst))
+}
+
+// ログイン時の自動起動の登録状態を enabled に合わせる
+// （Windows は Run レジストリ、macOS は LaunchAgent、Linux は autostart の .desktop）
+fn sync_autostart(app_handle: &tauri::AppHandle, enabled: bool) -> Result<(), String> {
+    let autolaunch = app_handle.autolaunch();
+    let current = autolaunch
+        .is_enabled()
+        .map_err(|e| format!("autostart error: {}", e))?;
+    if current == enabled {
+        return Ok(());
+    }
+    if enabled {
+        autolaunch.enable()
+    } else {
+        autolaunch.disable()
+    }
+    .map_err(|e| format!("autostart error: {}", e))
+}
+
+fn set_start_on_login(app_handle: &tauri::AppHandle, enabled: bool) -> Result<(), String> {
+    sync_autostart(app_handle, enabled)?;
+    let state = app_handle.state::<AppState>();
+    state.config.lock().unwrap().start_on_login = enabled;
+    mark_config_dirty(&state);
+    Ok(())
+}
+
+#[tauri::command]
+fn enable_autostart(app_handle: tauri::AppHandle) -> Result<(), String> {
+    set_start_on_login(&app_handle, true)
+}
+
+#[tauri::command]
+fn disable_autostart(app_handle: tauri::AppHandle) -> Result<(), String> {
+    set_start_on_login(&app_handle, false)
+}
+
+// 実際に OS に登録されているか（設定値ではなく OS 側の状態）
+#[tauri::command]
+fn get_autostart_status(app_handle: tauri::AppHandle) -> Result<bool, String> {
+    app_handle
+        .autolaunch()
+        .is_enabled()
+        .map_err(|e| format!("autostart error: {}", e))
+}
+
+// ウィンドウの位置・サイズはドラッグ中に大量に届くので、最後のイベントから
+// WINDOW_SAVE_DEBOUNCE 経ってから1回だけ書き込む
+fn schedule_window_save(app_handle: &tauri::AppHandle) {
+    let state = app_handle.state::<AppState>();
+    let generation = state.window_save_generation.fetch_add(1, Ordering::SeqCst) + 1;
+    let app_handle = app_handle.clone();
+    tauri::async_runtime::spawn(async move {
+        sleep(WINDOW_SAVE_DEBOUNCE).await;
+        let state = app_handle.state::<AppState>();
+        if state.window_save_generation.load(Ordering::SeqCst) != generation {
+            return;
+        }
+        let window_state = state.window_state.lock().unwrap().clone();
+        if let Err(e) = save_window_state(&window_state) {
+            warn!("failed to save window state: {e}");
+        }
+    });
+}
+
+fn window_state_path() -> Option<PathBuf> {
+    Some(config_dir().ok()?.join("window_state.json"))
+}
+
+// window_state.json が無ければ、以前のバージョンが設定ファイルに書いていた値を一度だけ引き継ぐ
+fn load_window_state() -> WindowState {
+    let Some(path) = window_state_path() else {
+        return WindowState::default();
+    };
+    if path.exists() {
+        return match fs::read_to_string(&path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                warn!("failed to parse window_state.json: {e}");
+                WindowState::default()
+            }),
+            Err(e) => {
+                warn!("failed to read window_state.json: {e}");
+                WindowState::default()
+            }
+        };
+    }
+
+    let legacy = legacy_window_state().unwrap_or_default();
+    if legacy != WindowState::default() {
+        if let Err(e) = save_window_state(&legacy) {
+            warn!("failed to save window state: {e}");
+        }
+    }
+    legacy
+}
+
+// 設定ファイルの windowWidth などを読む（AppConfig からは消したので生の値から取り出す）
+fn legacy_window_state() -> Option<WindowState> {
+    let config_path = config_path(&config_dir().ok()?);
+    let content = fs::read_to_string(&config_path).ok()?;
+    let value: serde_json::Value = if is_toml(&config_path) {
+        toml::from_str(&content).ok()?
     } else {
-        false
+        serde_json::from_str(&content).ok()?
+    };
+    serde_json::from_value(value).ok()
+}
+
+fn save_window_state(window_state: &WindowState) -> Result<(), String> {
+    let path = window_state_path().ok_or("failed to get exe dir")?;
+    let json = serde_json::to_string_pretty(window_state)
+        .map_err(|e| format!("serialize error: {}", e))?;
+    write_atomic(&path, &json)
+}
+
+// 保存した位置が今つながっているモニターのどれかに入っているか（外したモニターなら中央に出す）
+fn position_on_screen(window: &tauri::WebviewWindow, x: i32, y: i32) -> bool {
+    window.available_monitors().is_ok_and(|monitors| {
+        monitors.iter().any(|m| {
+            let pos = m.position();
+            let size = m.size();
+            x >= pos.x
+                && y >= pos.y
+                && x < pos.x + size.width as i32
+                && y < pos.y + size.height as i32
+        })
+    })
+}
+
+// 一時停止の切替。設定にも保存し、ループを起こして即座に反映する
+fn set_paused(app_handle: &tauri::AppHandle, paused: bool) -> Result<(), String> {
+    let state = app_handle.state::<AppState>();
+    state.config.lock().unwrap().paused = paused;
+    mark_config_dirty(&state);
+    state.notify.notify_one();
+
+    if let Some(tray_items) = app_handle.try_state::<TrayItems>() {
+        let _ = tray_items.toggle_pause.set_text(pause_label(paused));
     }
+    update_tray(app_handle);
+    Ok(())
 }
 
-fn collect_images_recursively(path: &Path) -> Vec<PathBuf> {
-    let mut result = Vec::new();
+// トレイのツールチップに表示中の壁紙のファイル名を出し、アイコンを切り替える。
+// 停止中・対象なし・範囲外で表示していないときは idle 表示
+fn update_tray(app_handle: &tauri::AppHandle) {
+    let Some(tray) = app_handle.try_state::<TrayIcon>() else {
+        return;
+    };
+    let state = app_handle.state::<AppState>();
+    let idle = {
+        let cfg = state.config.lock().unwrap();
+        cfg.paused || !cfg.auto_rotate
+    };
+    let (active, shown) = {
+        let rotation = state.rotation.lock().unwrap();
+        (rotation.random_active, rotation.last_shown.clone())
+    };
 
-    if path.is_file() {
-        if is_image_file(path) {
-            result.push(path.to_path_buf());
+    let rotating = active && !idle;
+    let name = shown
+        .filter(|_| rotating)
+        .and_then(|p| p.file_name().map(|n| n.to_string_lossy().to_string()));
+    let text = match name {
+        Some(name) if name.chars().count() > TOOLTIP_MAX_CHARS => {
+            let head: String = name.chars().take(TOOLTIP_MAX_CHARS - 1).collect();
+            format!("{head}…")
         }
-    } else if path.is_dir() {
-        if let Ok(entries) = std::fs::read_dir(path) {
-            for entry in entries.flatten() {
-                let p = entry.path();
-                if p.is_dir() {
-                    result.extend(collect_images_recursively(&p));
-                } else if is_image_file(&p) {
-                    result.push(p);
-                }
+        Some(name) => name,
+        None => IDLE_TOOLTIP.to_string(),
+    };
+    let _ = tray.set_tooltip(Some(text));
+
+    if let Some(tray_items) = app_handle.try_state::<TrayItems>() {
+        let mut showing_active = tray_items.showing_active.lock().unwrap();
+        if *showing_active != rotating {
+            let icon = if rotating {
+                &tray_items.active_icon
+            } else {
+                &tray_items.idle_icon
+            };
+            if tray.set_icon(Some(icon.clone())).is_ok() {
+                *showing_active = rotating;
             }
         }
     }
+}
 
-    result
+#[tauri::command]
+fn pause_rotation(app_handle: tauri::AppHandle) -> Result<(), String> {
+    set_paused(&app_handle, true)
 }
 
 #[tauri::command]
-fn save_config(app_handle: tauri::AppHandle, config: AppConfig) -> Result<(), String> {
-    let exe_path = std::env::current_exe().map_err(|e| e.to_string())?;
-    let exe_dir = exe_path.parent().ok_or("failed to get exe dir")?;
-    let config_path = exe_dir.join("config.json");
+fn resume_rotation(app_handle: tauri::AppHandle) -> Result<(), String> {
+    set_paused(&app_handle, false)
+}
 
-    let mut merged = config.clone();
-    if merged.file_targets.is_empty() && config_path.exists() {
-        if let Ok(content) = std::fs::read_to_string(&config_path) {
-            if let Ok(existing_cfg) = serde_json::from_str::<AppConfig>(&content) {
-                if !existing_cfg.file_targets.is_empty() {
-                    merged.file_targets = existing_cfg.file_targets;
-                }
-            }
-        }
+// minutes 分だけ自動切替を止め、過ぎたら自動で再開する（一時停止と違って保存しない）
+#[tauri::command]
+fn snooze(app_handle: tauri::AppHandle, minutes: u64) -> Result<(), String> {
+    if minutes == 0 {
+        return Err("snooze minutes must be greater than 0".to_string());
     }
+    let minutes = minutes.min(i32::MAX as u64) as i64;
+    let state = app_handle.state::<AppState>();
+    *state.snooze_until.lock().unwrap() = Some(Local::now() + chrono::Duration::minutes(minutes));
+    state.notify.notify_one();
+    Ok(())
+}
 
-    let json =
-        serde_json::to_string_pretty(&merged).map_err(|e| format!("serialize error: {}", e))?;
+#[tauri::command]
+fn cancel_snooze(app_handle: tauri::AppHandle) {
+    let state = app_handle.state::<AppState>();
+    *state.snooze_until.lock().unwrap() = None;
+    state.notify.notify_one();
+}
+
+// 今が実行時間帯（曜日・日付・開始/終了・DND）の中か。ループと同じく動作中かどうかで終了判定が変わる
+#[tauri::command]
+fn get_schedule_state(app_handle: tauri::AppHandle) -> bool {
+    in_schedule(&app_handle.state::<AppState>())
+}
+
+fn in_schedule(state: &AppState) -> bool {
+    let currently_active = state.rotation.lock().unwrap().random_active;
+    let cfg = state.config.lock().unwrap().clone();
+    should_run(Local::now(), &cfg, currently_active)
+}
+
+#[tauri::command]
+fn get_log_path() -> Result<String, String> {
+    let path = log_path().ok_or("failed to get exe dir")?;
+    Ok(path.to_string_lossy().to_string())
+}
 
-    std::fs::write(&config_path, json).map_err(|e| format!("write error: {}", e))?;
-    //println!("save: {} {:?}", config_path.display(), merged);
+// 元の壁紙にすぐ戻し、再開するまで自動切替を止める（画面共有の前など）
+#[tauri::command]
+fn restore_original(app_handle: tauri::AppHandle) -> Result<(), String> {
     let state = app_handle.state::<AppState>();
+    let initial = state.initial_wallpaper.lock().unwrap().clone();
+    let path = initial
+        .or_else(|| state.config.lock().unwrap().initial_wallpaper.clone())
+        .ok_or("no initial wallpaper captured")?;
+
+    // 先に止めておかないとループがすぐ上書きする
+    set_paused(&app_handle, true)?;
+    // 切替中の書き戻しで random_active / last_shown を上書きされないよう切替と順番にする
+    let _stepping = state.step_lock.lock().unwrap();
+    if !apply_and_emit(&app_handle, &path, None, false, "restore") {
+        return Err(format!("failed to restore: {}", path.display()));
+    }
     {
-        let mut cfg = state.config.lock().unwrap();
-        *cfg = merged.clone();
-        // also update the remembered last_random_enabled so the main loop can detect toggles
-        let mut last_rand = state.last_random_enabled.lock().unwrap();
-        *last_rand = merged.random;
+        let mut rotation = state.rotation.lock().unwrap();
+        rotation.random_active = false;
+        rotation.last_shown = None;
     }
+    update_tray(&app_handle);
+    Ok(())
+}
 
-    state.notify.notify_one();
+// メモリ上の設定（と有効なら履歴）を今すぐディスクへ書き出す
+#[tauri::command]
+fn flush_state(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let state = app_handle.state::<AppState>();
+    mark_config_dirty(&state);
+    flush_config(&state)?;
+
+    if state.config.lock().unwrap().persist_history {
+        let history = state.history_log.lock().unwrap();
+        save_history(&history)?;
+    }
 
     Ok(())
 }
 
+// 新しい順に最大 limit 件の (タイムスタンプ, パス) を返す
 #[tauri::command]
-fn load_config_for_frontend() -> Result<AppConfig, String> {
-    let exe_path = std::env::current_exe().map_err(|e| e.to_string())?;
-    let exe_dir = exe_path.parent().ok_or("failed to get exe dir")?;
-    let config_path = exe_dir.join("config.json");
+fn get_history(app_handle: tauri::AppHandle, limit: usize) -> Vec<(String, String)> {
+    let state = app_handle.state::<AppState>();
+    let history = state.history_log.lock().unwrap();
+    history
+        .iter()
+        .rev()
+        .take(limit)
+        .map(|entry| {
+            (
+                entry.shown_at.to_rfc3339(),
+                entry.path.to_string_lossy().to_string(),
+            )
+        })
+        .collect()
+}
 
-    if !config_path.exists() {
-        return Ok(AppConfig::default());
-    }
+// CSV の1フィールド分をクォートする
+fn csv_field(value: &str) -> String {
+    format!("\"{}\"", value.replace('"', "\"\""))
+}
+
+// 履歴を CSV / JSON で dest に書き出し、書き出したパスを返す
+#[tauri::command]
+fn export_history(
+    app_handle: tauri::AppHandle,
+    dest: String,
+    format: String,
+) -> Result<String, String> {
+    let entries = {
+        let state = app_handle.state::<AppState>();
+        let history = state.history_log.lock().unwrap();
+        history.clone()
+    };
+
+    let content = match format.to_ascii_lowercase().as_str() {
+        "json" => {
+            serde_json::to_string_pretty(&entries).map_err(|e| format!("serialize error: {}", e))?
+        }
+        "csv" => {
+            let mut out = String::from("shownAt,path,mode,playlist\n");
+            for entry in &entries {
+                out.push_str(&format!(
+                    "{},{},{},{}\n",
+                    csv_field(&entry.shown_at.to_rfc3339()),
+                    csv_field(&entry.path.to_string_lossy()),
+                    csv_field(&entry.mode),
+                    csv_field(entry.playlist.as_deref().unwrap_or_default())
+                ));
+            }
+            out
+        }
+        other => return Err(format!("unsupported export format: {}", other)),
+    };
+
+    let dest_path = PathBuf::from(&dest);
+    std::fs::write(&dest_path, content).map_err(|e| format!("write error: {}", e))?;
 
-    let content = std::fs::read_to_string(&config_path)
-        .map_err(|e| format!("failed to read config.json: {}", e))?;
+    Ok(dest_path.to_string_lossy().to_string())
+}
 
-    let cfg: AppConfig = serde_json::from_str(&content)
-        .map_err(|e| format!("failed to parse config.json: {}", e))?;
+// 設定ファイルは変更から最大 1 秒遅れて書かれるので、今使っている設定（コマンドライン引数の上書き込み）を返す
+#[tauri::command]
+fn load_config_for_frontend(app_handle: tauri::AppHandle) -> AppConfig {
+    app_handle
+        .state::<AppState>()
+        .config
+        .lock()
+        .unwrap()
+        .clone()
+}
 
-    Ok(cfg)
+// フォルダーを画像ファイルに展開し、まだ入っていないものを targets に追加する
+fn append_images(targets: &mut Vec<PathBuf>, paths: Vec<String>, scan: ScanOptions) {
+    for p in paths {
+        let path = PathBuf::from(&p);
+        let imgs = collect_images_recursively(&path, scan);
+        for img in imgs {
+            // UTF-8 にできないパスは config.json に保存できないので除外する
+            if img.to_str().is_none() {
+                warn!("skipping non-UTF-8 path: {}", img.display());
+                continue;
+            }
+            // 重複排除（文字列に変換せず Path 同士で比較する）
+            if !targets.contains(&img) {
+                targets.push(img);
+            }
+        }
+    }
 }
 
 #[tauri::command]
@@ -326,48 +4432,379 @@ fn add_file_targets(
     app_handle: tauri::AppHandle,
     paths: Vec<String>,
 ) -> Result<Vec<String>, String> {
-    let exe_path = std::env::current_exe().map_err(|e| e.to_string())?;
-    let exe_dir = exe_path.parent().ok_or("failed to get exe dir")?;
-    let config_path = exe_dir.join("config.json");
     //println!("save path: {:?}", paths);
-    // config.json を読み込み
-    let mut cfg = if config_path.exists() {
-        let content =
-            std::fs::read_to_string(&config_path).map_err(|e| format!("read error: {}", e))?;
-        serde_json::from_str::<AppConfig>(&content).map_err(|e| format!("parse error: {}", e))?
-    } else {
-        AppConfig::default()
+    // 追加されたパスを展開して重複排除
+    let (file_targets, sampled) = update_config(&app_handle.state::<AppState>(), |cfg| {
+        let scan = ScanOptions::from_config(cfg);
+        let existing = cfg.file_targets.len();
+        append_images(&mut cfg.file_targets, paths, scan);
+        let sampled = cfg
+            .max_targets
+            .and_then(|limit| sample_new_targets(&mut cfg.file_targets, existing, limit));
+        Ok((cfg.file_targets.clone(), sampled))
+    })?;
+    app_handle.state::<AppState>().notify.notify_one();
+    if let Some(sampled) = sampled {
+        warn!(
+            "max_targets: added {} of {} new images (limit {})",
+            sampled.added, sampled.found, sampled.limit
+        );
+        let _ = app_handle.emit("targets-sampled", &sampled);
+    }
+
+    // フロントへ返す（文字列配列）
+    Ok(file_targets
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect())
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TargetsSampled {
+    // new images found by the scan
+    found: usize,
+    // how many of them were kept
+    added: usize,
+    limit: usize,
+}
+
+// targets[existing..] の追加分が limit を超える分だけ、ランダムに選んで残す。
+// 元からある画像は limit を超えていても減らさず、残す画像は元の順番のままにする
+fn sample_new_targets(
+    targets: &mut Vec<PathBuf>,
+    existing: usize,
+    limit: usize,
+) -> Option<TargetsSampled> {
+    if targets.len() <= limit.max(existing) {
+        return None;
+    }
+    let added = targets.split_off(existing);
+    let room = limit.saturating_sub(existing);
+    let mut keep = rand::seq::index::sample(&mut make_rng(None), added.len(), room).into_vec();
+    keep.sort_unstable();
+    targets.extend(keep.into_iter().map(|i| added[i].clone()));
+    Some(TargetsSampled {
+        found: added.len(),
+        added: room,
+        limit,
+    })
+}
+
+// メモリ上の config を更新して書き出しの印を付ける
+fn update_config<T>(
+    state: &AppState,
+    f: impl FnOnce(&mut AppConfig) -> Result<T, String>,
+) -> Result<T, String> {
+    let result = {
+        let mut cfg = state.config.lock().unwrap();
+        let mut updated = cfg.clone();
+        let result = f(&mut updated)?;
+        *cfg = updated;
+        result
     };
+    mark_config_dirty(state);
+    Ok(result)
+}
 
-    // 追加されたパスを展開
-    let mut new_files = Vec::new();
-    for p in paths {
-        let path = PathBuf::from(&p);
-        let imgs = collect_images_recursively(&path);
-        for img in imgs {
-            new_files.push(img.to_string_lossy().to_string());
+// 選択中のプレイリストが変わったら次の画像は先頭から選び直す
+fn reset_rotation(state: &AppState) {
+    // 切替中なら終わるのを待つ（書き戻しで消されないように）
+    let _stepping = state.step_lock.lock().unwrap();
+    state.rotation.lock().unwrap().current_index = None;
+    request_advance(state);
+}
+
+#[tauri::command]
+fn create_playlist(app_handle: tauri::AppHandle, name: String) -> Result<(), String> {
+    let name = name.trim().to_string();
+    if name.is_empty() {
+        return Err("playlist name is empty".to_string());
+    }
+    update_config(&app_handle.state::<AppState>(), |cfg| {
+        if cfg.playlists.contains_key(&name) {
+            return Err(format!("playlist already exists: {}", name));
+        }
+        cfg.playlists.insert(name, Playlist::default());
+        Ok(())
+    })
+}
+
+#[tauri::command]
+fn delete_playlist(app_handle: tauri::AppHandle, name: String) -> Result<(), String> {
+    let was_active = update_config(&app_handle.state::<AppState>(), |cfg| {
+        cfg.playlists
+            .remove(&name)
+            .ok_or_else(|| format!("unknown playlist: {}", name))?;
+        let was_active = cfg.active_playlist.as_deref() == Some(name.as_str());
+        if was_active {
+            cfg.active_playlist = None;
+        }
+        Ok(was_active)
+    })?;
+    if was_active {
+        reset_rotation(&app_handle.state::<AppState>());
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn add_to_playlist(
+    app_handle: tauri::AppHandle,
+    name: String,
+    paths: Vec<String>,
+) -> Result<Vec<String>, String> {
+    let (targets, active) = update_config(&app_handle.state::<AppState>(), |cfg| {
+        let scan = ScanOptions::from_config(cfg);
+        let playlist = cfg
+            .playlists
+            .get_mut(&name)
+            .ok_or_else(|| format!("unknown playlist: {}", name))?;
+        append_images(&mut playlist.targets, paths, scan);
+        let targets = playlist.targets.clone();
+        Ok((
+            targets,
+            cfg.active_playlist.as_deref() == Some(name.as_str()),
+        ))
+    })?;
+    if active {
+        app_handle.state::<AppState>().notify.notify_one();
+    }
+
+    Ok(targets
+        .iter()
+        .map(|p| p.to_string_lossy().to_string())
+        .collect())
+}
+
+// ランダム切替での画像の重みを設定する（1 は既定値なので保存しない、0 は選ばれなくなる）
+#[tauri::command]
+fn set_weight(app_handle: tauri::AppHandle, path: String, weight: u32) -> Result<(), String> {
+    update_config(&app_handle.state::<AppState>(), |cfg| {
+        let path = PathBuf::from(&path);
+        if weight == 1 {
+            cfg.weights.remove(&path);
+        } else {
+            cfg.weights.insert(path, weight);
+        }
+        Ok(())
+    })
+}
+
+// プレイリスト固有の interval / random を設定する（None ならトップレベルの設定に従う）
+#[tauri::command]
+fn set_playlist_options(
+    app_handle: tauri::AppHandle,
+    name: String,
+    interval: Option<u64>,
+    random: Option<bool>,
+) -> Result<(), String> {
+    update_playlist_options(&app_handle.state::<AppState>(), name, interval, random)
+}
+
+fn update_playlist_options(
+    state: &AppState,
+    name: String,
+    interval: Option<u64>,
+    random: Option<bool>,
+) -> Result<(), String> {
+    if interval == Some(0) {
+        return Err("playlist interval must be greater than 0".to_string());
+    }
+    let active = update_config(state, |cfg| {
+        let playlist = cfg
+            .playlists
+            .get_mut(&name)
+            .ok_or_else(|| format!("unknown playlist: {}", name))?;
+        playlist.interval = interval;
+        playlist.random = random;
+        Ok(cfg.active_playlist.as_deref() == Some(name.as_str()))
+    })?;
+    // 選択中なら今の待ちを打ち切って新しい間隔で次の切替時刻を決め直させる
+    if active {
+        request_reschedule(state);
+    }
+    Ok(())
+}
+
+// name が None なら通常の fileTargets に戻す
+// 切替先の interval / random はループが次の周回で読み直す
+#[tauri::command]
+fn switch_playlist(app_handle: tauri::AppHandle, name: Option<String>) -> Result<(), String> {
+    update_config(&app_handle.state::<AppState>(), |cfg| {
+        if let Some(name) = &name {
+            if !cfg.playlists.contains_key(name) {
+                return Err(format!("unknown playlist: {}", name));
+            }
+        }
+        cfg.active_playlist = name;
+        Ok(())
+    })?;
+    reset_rotation(&app_handle.state::<AppState>());
+    Ok(())
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct TagCount {
+    tag: String,
+    count: usize,
+}
+
+#[tauri::command]
+fn add_tag(app_handle: tauri::AppHandle, path: String, tag: String) -> Result<(), String> {
+    let tag = tag.trim().to_string();
+    if tag.is_empty() {
+        return Err("tag is empty".to_string());
+    }
+    let filtered = update_config(&app_handle.state::<AppState>(), |cfg| {
+        let tags = cfg.tags.entry(PathBuf::from(&path)).or_default();
+        if !tags.contains(&tag) {
+            tags.push(tag);
+        }
+        Ok(!cfg.active_tags.is_empty())
+    })?;
+    // 絞り込み中なら対象が変わるのでループに反映させる
+    if filtered {
+        app_handle.state::<AppState>().notify.notify_one();
+    }
+    Ok(())
+}
+
+#[tauri::command]
+fn remove_tag(app_handle: tauri::AppHandle, path: String, tag: String) -> Result<(), String> {
+    let filtered = update_config(&app_handle.state::<AppState>(), |cfg| {
+        let path = PathBuf::from(&path);
+        if let Some(tags) = cfg.tags.get_mut(&path) {
+            tags.retain(|t| t != &tag);
+            if tags.is_empty() {
+                cfg.tags.remove(&path);
+            }
         }
+        Ok(!cfg.active_tags.is_empty())
+    })?;
+    if filtered {
+        app_handle.state::<AppState>().notify.notify_one();
     }
+    Ok(())
+}
+
+// 空配列で絞り込みを解除する
+#[tauri::command]
+fn set_active_tags(app_handle: tauri::AppHandle, tags: Vec<String>) -> Result<(), String> {
+    update_config(&app_handle.state::<AppState>(), |cfg| {
+        cfg.active_tags = tags;
+        Ok(())
+    })?;
+    reset_rotation(&app_handle.state::<AppState>());
+    Ok(())
+}
 
-    // 重複排除
-    for f in new_files.iter() {
-        if !cfg.file_targets.iter().any(|x| x.to_string_lossy() == *f) {
-            cfg.file_targets.push(PathBuf::from(f));
+// 使われているタグと付いている画像の数（タグ名順）
+#[tauri::command]
+fn list_tags(app_handle: tauri::AppHandle) -> Vec<TagCount> {
+    let state = app_handle.state::<AppState>();
+    let cfg = state.config.lock().unwrap();
+    let mut counts: HashMap<&str, usize> = HashMap::new();
+    for tags in cfg.tags.values() {
+        for tag in tags {
+            *counts.entry(tag).or_default() += 1;
         }
     }
+    let mut list: Vec<TagCount> = counts
+        .into_iter()
+        .map(|(tag, count)| TagCount {
+            tag: tag.to_string(),
+            count,
+        })
+        .collect();
+    list.sort_by(|a, b| a.tag.cmp(&b.tag));
+    list
+}
+
+#[derive(Serialize, Clone, Debug)]
+#[serde(rename_all = "camelCase")]
+struct StatEntry {
+    path: String,
+    count: u64,
+}
+
+// 表示回数の多い順
+#[tauri::command]
+fn get_stats(app_handle: tauri::AppHandle) -> Vec<StatEntry> {
+    let state = app_handle.state::<AppState>();
+    let counts = state.show_counts.lock().unwrap();
+    let mut stats: Vec<StatEntry> = counts
+        .iter()
+        .map(|(path, count)| StatEntry {
+            path: path.to_string_lossy().to_string(),
+            count: *count,
+        })
+        .collect();
+    stats.sort_by(|a, b| b.count.cmp(&a.count).then_with(|| a.path.cmp(&b.path)));
+    stats
+}
 
-    // 保存
-    let json = serde_json::to_string_pretty(&cfg).map_err(|e| format!("serialize error: {}", e))?;
-    std::fs::write(&config_path, json).map_err(|e| format!("write error: {}", e))?;
+#[tauri::command]
+fn reset_stats(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let state = app_handle.state::<AppState>();
+    state.show_counts.lock().unwrap().clear();
+    state.stats_dirty.store(false, Ordering::SeqCst);
+    save_stats(&HashMap::new())
+}
 
+// file_targets を全て削除する。save_config の「空なら既存を残す」処理は通さずに直接保存する
+// （対象が無くなるので次のループで元の壁紙に戻る）
+#[tauri::command]
+fn clear_file_targets(app_handle: tauri::AppHandle) -> Result<(), String> {
+    let state = app_handle.state::<AppState>();
     {
-        let state = app_handle.state::<AppState>();
-        let mut state_cfg = state.config.lock().unwrap();
-        state_cfg.file_targets = cfg.file_targets.clone();
-        state.notify.notify_one();
+        let mut cfg = state.config.lock().unwrap();
+        cfg.file_targets.clear();
+        prune_image_settings(&mut cfg);
+    }
+    mark_config_dirty(&state);
+    state.notify.notify_one();
+    Ok(())
+}
+
+// file_targets の並べ替え（順番切替の順序になる）
+#[tauri::command]
+fn reorder_file_targets(
+    app_handle: tauri::AppHandle,
+    from: usize,
+    to: usize,
+) -> Result<Vec<String>, String> {
+    let state = app_handle.state::<AppState>();
+    let cfg = {
+        let mut cfg = state.config.lock().unwrap();
+        let len = cfg.file_targets.len();
+        if from >= len || to >= len {
+            return Err(format!(
+                "index out of range: {} -> {} (len {})",
+                from, to, len
+            ));
+        }
+        let item = cfg.file_targets.remove(from);
+        cfg.file_targets.insert(to, item);
+        cfg.clone()
+    };
+    mark_config_dirty(&state);
+
+    // 表示中の画像の次から続くように、次に表示する位置を付け直す
+    {
+        let mut rotation = state.rotation.lock().unwrap();
+        if rotation.current_index.is_some() {
+            if let Some(pos) = rotation
+                .last_shown
+                .as_ref()
+                .and_then(|shown| cfg.file_targets.iter().position(|p| p == shown))
+            {
+                rotation.current_index = Some((pos + 1) % cfg.file_targets.len());
+            }
+        }
     }
 
-    // フロントへ返す（文字列配列）
     Ok(cfg
         .file_targets
         .iter()
@@ -377,44 +4814,51 @@ fn add_file_targets(
 
 #[tauri::command]
 fn remove_file_target(app_handle: tauri::AppHandle, path: String) -> Result<Vec<String>, String> {
-    let exe_path = std::env::current_exe().map_err(|e| e.to_string())?;
-    let exe_dir = exe_path.parent().ok_or("failed to get exe dir")?;
-    let config_path = exe_dir.join("config.json");
     //println!("save path(remove): {}", path);
-
-    let mut cfg = if config_path.exists() {
-        // config.json を読み込み
-        let content =
-            std::fs::read_to_string(&config_path).map_err(|e| format!("read error: {}", e))?;
-        serde_json::from_str::<AppConfig>(&content).map_err(|e| format!("parse error: {}", e))?
-    } else {
-        AppConfig::default()
-    };
-
     // 削除
-    cfg.file_targets.retain(|p| p.to_string_lossy() != path);
-
-    // 保存
-    let json = serde_json::to_string_pretty(&cfg).map_err(|e| format!("serialize error: {}", e))?;
-    std::fs::write(&config_path, json).map_err(|e| format!("write error: {}", e))?;
-
-    {
-        let state = app_handle.state::<AppState>();
-        let mut state_cfg = state.config.lock().unwrap();
-        state_cfg.file_targets = cfg.file_targets.clone();
-        state.notify.notify_one();
-    }
+    let file_targets = update_config(&app_handle.state::<AppState>(), |cfg| {
+        cfg.file_targets.retain(|p| p != Path::new(&path));
+        prune_image_settings(cfg);
+        Ok(cfg.file_targets.clone())
+    })?;
+    app_handle.state::<AppState>().notify.notify_one();
 
     // 最新の fileTargets を返す
-    Ok(cfg
-        .file_targets
+    Ok(file_targets
         .iter()
         .map(|p| p.to_string_lossy().to_string())
         .collect())
 }
 
 fn main() {
+    init_logging();
+    // コマンドライン引数は二重起動の判定より前に解釈する（--help や引数の誤りは既存のインスタンスに渡さずここで表示して終了する）
+    let cli_overrides = match parse_cli_args(std::env::args().skip(1)) {
+        Ok(Some(overrides)) => overrides,
+        Ok(None) => {
+            attach_console();
+            println!("{CLI_HELP}");
+            std::process::exit(0);
+        }
+        Err(e) => {
+            attach_console();
+            eprintln!("{e}\n\n{CLI_HELP}");
+            std::process::exit(2);
+        }
+    };
     tauri::Builder::default()
+        // 二重起動時は既存のウィンドウを表示して新しい方は終了する（最初に登録する必要がある）
+        .plugin(tauri_plugin_single_instance::init(|app, args, _cwd| {
+            // 後から起動された側の引数は既存のインスタンスには反映しない
+            if args.len() > 1 {
+                warn!(
+                    "already running; ignored command line options: {}",
+                    args[1..].join(" ")
+                );
+            }
+            show_main_window(app)
+        }))
+        .plugin(tauri_plugin_autostart::init(MacosLauncher::LaunchAgent, None))
         .plugin(tauri_plugin_clipboard_manager::init())
         .plugin(tauri_plugin_fs::init())
         .plugin(tauri_plugin_shell::init())
@@ -426,52 +4870,235 @@ fn main() {
         .plugin(tauri_plugin_global_shortcut::Builder::new().build())
         .invoke_handler(tauri::generate_handler![
             save_config,
+            reload_config,
             load_config_for_frontend,
             add_file_targets,
-            remove_file_target
+            remove_file_target,
+            reorder_file_targets,
+            clear_file_targets,
+            create_playlist,
+            delete_playlist,
+            add_to_playlist,
+            switch_playlist,
+            set_playlist_options,
+            add_tag,
+            remove_tag,
+            set_active_tags,
+            list_tags,
+            get_stats,
+            set_weight,
+            reset_stats,
+            get_history,
+            export_history,
+            flush_state,
+            preview_at,
+            get_thumbnail,
+            get_image_dimensions,
+            next_wallpaper,
+            prev_wallpaper,
+            set_current_index,
+            set_specific_wallpaper,
+            peek_next,
+            set_current_as_default,
+            reveal_current_wallpaper,
+            copy_current_path,
+            get_next_change,
+            get_last_error,
+            get_rotation_status,
+            get_power_state,
+            pause_rotation,
+            resume_rotation,
+            snooze,
+            cancel_snooze,
+            restore_original,
+            get_schedule_state,
+            get_log_path,
+            open_config_dir,
+            import_config,
+            validate_config,
+            get_supported_extensions,
+            enable_autostart,
+            disable_autostart,
+            get_autostart_status
         ])
-        .setup(|app| {
-            let initial_wallpaper = get_current_wallpaper();
-            let config = load_config_from_exe_dir();
+        .setup(move |app| {
+            let current_wallpaper = get_current_wallpaper();
+            let mut config = load_config_from_exe_dir();
+            if let Some(level) = log_level_filter(config.log_level.as_deref()) {
+                log::set_max_level(level);
+            }
+
+            // 切替対象の画像が表示されている = 前回は異常終了して元に戻せなかった
+            let showing_target = current_wallpaper
+                .as_ref()
+                .is_some_and(|current| is_rotation_target(&config, current));
+            let initial_wallpaper = match (showing_target, config.initial_wallpaper.clone()) {
+                (true, Some(stored)) => {
+                    // 保存しておいた元の壁紙に戻す（記録は上書きしない）
+                    warn!(
+                        "current wallpaper is a rotation target; restoring stored initial {}",
+                        stored.display()
+                    );
+                    let _ = set_wallpaper(&stored);
+                    Some(stored)
+                }
+                (true, None) => {
+                    // 切替対象の画像を「元の壁紙」として記録すると本来の壁紙が失われるので記録しない
+                    warn!(
+                        "current wallpaper is a rotation target and no initial is stored; not capturing it"
+                    );
+                    None
+                }
+                (false, _) => {
+                    // 今の壁紙を元の壁紙として記録しておく
+                    if current_wallpaper.is_some() && config.initial_wallpaper != current_wallpaper
+                    {
+                        config.initial_wallpaper = current_wallpaper.clone();
+                        let saved = config_dir().and_then(|dir| write_config_file(&dir, &config));
+                        if let Err(e) = saved {
+                            error!("failed to save initial wallpaper: {e}");
+                        }
+                    }
+                    current_wallpaper
+                }
+            };
 
-            if let Some(win) = app.get_webview_window("wallpaper_changer") {
-                if let (Some(w), Some(h)) = (config.window_width, config.window_height) {
+            // コマンドライン引数で今回だけ設定を上書きする（--save なら保存もする）
+            let before = config.clone();
+            let save = cli_overrides.save;
+            apply_cli_overrides(&mut config, cli_overrides);
+            if save {
+                if let Err(e) = write_config(&config) {
+                    error!("failed to save command line overrides: {e}");
+                }
+            } else if config != before {
+                let _ = CLI_CONFIGS.set((before, config.clone()));
+            }
+
+            let window_state = load_window_state();
+            if config.headless {
+                // ヘッドレスでは tauri.conf.json から作られたウィンドウを破棄し、トレイと切替ループだけで動く
+                if let Some(win) = app.get_webview_window("wallpaper_changer") {
+                    let _ = win.destroy();
+                }
+            } else if let Some(win) = app.get_webview_window("wallpaper_changer") {
+                // 最大化で終了した場合はサイズを戻さない（set_size で最大化が解除されるため）
+                let maximized = window_state.maximized == Some(true);
+                if let (Some(w), Some(h), false) =
+                    (window_state.width, window_state.height, maximized)
+                {
+                    // 保存値は論理ピクセル（Resized で scale_factor で割って記録している）
                     let _ = win.set_size(Size::Logical(LogicalSize {
                         width: w as f64,
                         height: h as f64,
                     }));
                 }
+                match (window_state.x, window_state.y) {
+                    (Some(x), Some(y)) if position_on_screen(&win, x, y) => {
+                        let _ = win.set_position(PhysicalPosition { x, y });
+                    }
+                    (Some(_), Some(_)) => {
+                        let _ = win.center();
+                    }
+                    _ => {}
+                }
+                // ウィンドウは非表示で作られる（tauri.conf.json の visible: false）。
+                // start_hidden ならトレイにだけ常駐し、表示する場合は前回の最大化・最小化状態を再現する
+                // （maximize は非表示のウィンドウも表示してしまうので start_hidden では呼ばない）
+                if !config.start_hidden {
+                    let _ = win.show();
+                    if maximized {
+                        let _ = win.maximize();
+                    }
+                    if window_state.minimized == Some(true) {
+                        let _ = win.minimize();
+                    }
+                }
             }
 
-            app.manage(AppState::new(initial_wallpaper, config));
+            let startup_config = config.clone();
+            let paused = config.paused;
+            app.manage(AppState::new(initial_wallpaper, config, window_state));
+            // テーマはウィンドウから取る（ヘッドレスや判定できない環境では None のまま）
+            if let Some(win) = app.get_webview_window("wallpaper_changer") {
+                *app.state::<AppState>().os_theme.lock().unwrap() = win.theme().ok();
+            }
+            register_hotkeys(app.handle(), &startup_config);
+            watch_folder_targets(app.handle(), &startup_config.folder_targets);
+            watch_config_file(app.handle());
+            watch_power_events(app.handle());
 
             // Tauri v2 システムトレイ
             let show_item = MenuItem::with_id(app, "show", "表示", true, None::<&str>)?;
+            let next_item = MenuItem::with_id(app, "next", "次へ", true, None::<&str>)?;
+            let pause_item = MenuItem::with_id(
+                app,
+                "toggle_pause",
+                pause_label(paused),
+                true,
+                None::<&str>,
+            )?;
             let quit_item = MenuItem::with_id(app, "quit", "閉じる", true, None::<&str>)?;
-            let menu = Menu::with_items(app, &[
-                &show_item,
-                &PredefinedMenuItem::separator(app)?,
-                &quit_item,
-            ])?;
+            let menu = Menu::with_items(
+                app,
+                &[
+                    &show_item,
+                    &next_item,
+                    &pause_item,
+                    &PredefinedMenuItem::separator(app)?,
+                    &quit_item,
+                ],
+            )?;
+
+            // 起動直後から保存済みの一時停止状態に合ったアイコンにする
+            let active_icon = app.default_window_icon().unwrap().clone().to_owned();
+            let idle_icon = idle_icon_from(&active_icon);
+            let initial_icon = if paused {
+                idle_icon.clone()
+            } else {
+                active_icon.clone()
+            };
 
             let tray = TrayIconBuilder::new()
                 .menu(&menu)
-                .icon(app.default_window_icon().unwrap().clone())
+                .icon(initial_icon)
+                .tooltip(IDLE_TOOLTIP)
                 .on_menu_event(|app, event| match event.id.as_ref() {
-                    "show" => {
-                        if let Some(window) = app.get_webview_window("wallpaper_changer") {
-                            let _ = window.unminimize();
-                            let _ = window.show();
-                            let _ = window.set_focus();
+                    "show" => show_main_window(app),
+                    "next" => {
+                        if let Err(e) = manual_step(app, true) {
+                            warn!("tray next failed: {e}");
+                        }
+                    }
+                    "toggle_pause" => {
+                        let paused = app.state::<AppState>().config.lock().unwrap().paused;
+                        if let Err(e) = set_paused(app, !paused) {
+                            warn!("tray pause toggle failed: {e}");
                         }
                     }
                     "quit" => {
-                        let state_ref = app.state::<AppState>();
-                        let initial = state_ref.initial_wallpaper.lock().unwrap().clone();
-                        if let Some(path) = initial {
-                            set_wallpaper(&path);
+                        let confirm = app.state::<AppState>().config.lock().unwrap().confirm_on_quit;
+                        if !confirm {
+                            restore_on_exit(app);
+                            app.exit(0);
+                            return;
                         }
-                        app.exit(0);
+                        // メニューのイベントはメインスレッドで届くので、待たずに結果をコールバックで受け取る
+                        let app = app.clone();
+                        app.dialog()
+                            .message("壁紙の切り替えを終了しますか？")
+                            .title("Wallpaper Changer")
+                            .kind(MessageDialogKind::Warning)
+                            .buttons(MessageDialogButtons::OkCancelCustom(
+                                "終了".to_string(),
+                                "キャンセル".to_string(),
+                            ))
+                            .show(move |confirmed| {
+                                if confirmed {
+                                    restore_on_exit(&app);
+                                    app.exit(0);
+                                }
+                            });
                     }
                     _ => {}
                 })
@@ -501,6 +5128,12 @@ fn main() {
 
             // TrayIcon を管理下に置いてアプリ終了まで生存させる
             app.manage(tray);
+            app.manage(TrayItems {
+                toggle_pause: pause_item,
+                active_icon,
+                idle_icon,
+                showing_active: Mutex::new(!paused),
+            });
 
             Ok(())
         })
@@ -512,37 +5145,72 @@ fn main() {
                         return;
                     }
                     if let Ok(size) = window.inner_size() {
-                        let width = size.width;
-                        let height = size.height;
+                        // inner_size は物理ピクセルなので、復元時の LogicalSize に合わせて論理ピクセルで保存する
+                        // （拡大率 150% のモニターでも起動のたびにサイズが変わらないように）
+                        let scale = window.scale_factor().unwrap_or(1.0);
+                        let size = size.to_logical::<u32>(scale);
                         let minimized = window.is_minimized().unwrap_or(false);
-                        if let Ok(exe_path) = std::env::current_exe() {
-                            if let Some(exe_dir) = exe_path.parent() {
-                                let config_path = exe_dir.join("config.json");
-                                let mut cfg = if config_path.exists() {
-                                    std::fs::read_to_string(&config_path)
-                                        .ok()
-                                        .and_then(|s| serde_json::from_str::<AppConfig>(&s).ok())
-                                        .unwrap_or_else(AppConfig::default)
-                                } else {
-                                    AppConfig::default()
-                                };
-                                cfg.window_width = Some(width);
-                                cfg.window_height = Some(height);
-                                cfg.window_minimized = Some(minimized);
-                                if let Ok(json) = serde_json::to_string_pretty(&cfg) {
-                                    let _ = std::fs::write(&config_path, json);
-                                    let app_handle = window.app_handle();
-                                    let state_ref = app_handle.state::<AppState>();
-                                    let mut state_cfg = state_ref.config.lock().unwrap();
-                                    *state_cfg = cfg;
-                                }
+                        let maximized = window.is_maximized().unwrap_or(false);
+                        let app_handle = window.app_handle();
+                        {
+                            let state_ref = app_handle.state::<AppState>();
+                            let mut window_state = state_ref.window_state.lock().unwrap();
+                            // 最大化中のサイズは記録せず、元に戻したときのサイズを残しておく
+                            if !maximized {
+                                window_state.width = Some(size.width);
+                                window_state.height = Some(size.height);
                             }
+                            window_state.minimized = Some(minimized);
+                            window_state.maximized = Some(maximized);
                         }
+                        schedule_window_save(app_handle);
+                    }
+                }
+                WindowEvent::Moved(position) => {
+                    // 最小化すると画面外の座標 (-32000 など) に移動するので記録しない
+                    if window.is_minimized().unwrap_or(false) {
+                        return;
+                    }
+                    let app_handle = window.app_handle();
+                    {
+                        let state_ref = app_handle.state::<AppState>();
+                        let mut window_state = state_ref.window_state.lock().unwrap();
+                        window_state.x = Some(position.x);
+                        window_state.y = Some(position.y);
+                    }
+                    schedule_window_save(app_handle);
+                }
+                WindowEvent::ThemeChanged(theme) => {
+                    // ライト / ダーク用の画像があれば次の周期を待たずに切り替える
+                    let app_handle = window.app_handle();
+                    let state_ref = app_handle.state::<AppState>();
+                    *state_ref.os_theme.lock().unwrap() = Some(*theme);
+                    let has_theme_targets = {
+                        let cfg = state_ref.config.lock().unwrap();
+                        !cfg.light_targets.is_empty() || !cfg.dark_targets.is_empty()
+                    };
+                    if has_theme_targets {
+                        info!("OS theme changed: {theme:?}");
+                        invalidate_targets(&state_ref);
+                        request_advance(&state_ref);
                     }
                 }
                 WindowEvent::CloseRequested { api, .. } => {
-                    let _ = window.hide();
-                    api.prevent_close();
+                    let app_handle = window.app_handle();
+                    let close_to_tray = app_handle
+                        .state::<AppState>()
+                        .config
+                        .lock()
+                        .unwrap()
+                        .close_to_tray;
+                    if close_to_tray {
+                        let _ = window.hide();
+                        api.prevent_close();
+                    } else {
+                        // トレイの「閉じる」と同じく元の壁紙に戻してから終了する
+                        restore_on_exit(app_handle);
+                        app_handle.exit(0);
+                    }
                 }
                 _ => {}
             }
@@ -552,178 +5220,382 @@ fn main() {
         .run(|app_handle: &tauri::AppHandle, event| {
             match event {
                 RunEvent::Ready => {
+                    tauri::async_runtime::spawn_blocking(prune_thumbnail_cache);
+                    // 表示回数は変更があったときだけ定期的に書き出す
+                    {
+                        let app_handle = app_handle.clone();
+                        tauri::async_runtime::spawn(async move {
+                            loop {
+                                sleep(STATS_FLUSH_INTERVAL).await;
+                                flush_stats(&app_handle.state::<AppState>());
+                            }
+                        });
+                    }
+                    // 設定の変更も同様（こちらは編集がすぐディスクに残るよう間隔を短くする）
+                    {
+                        let app_handle = app_handle.clone();
+                        tauri::async_runtime::spawn(async move {
+                            loop {
+                                sleep(CONFIG_FLUSH_INTERVAL).await;
+                                if let Err(e) = flush_config(&app_handle.state::<AppState>()) {
+                                    error!("failed to save config: {e}");
+                                }
+                            }
+                        });
+                    }
+
+                    // 仮想デスクトップの切替を検出したらループを起こして対応する壁紙を適用する
+                    if cfg!(target_os = "windows") {
+                        let app_handle = app_handle.clone();
+                        tauri::async_runtime::spawn(async move {
+                            loop {
+                                sleep(Duration::from_secs(2)).await;
+
+                                let state_ref = app_handle.state::<AppState>();
+                                if state_ref
+                                    .config
+                                    .lock()
+                                    .unwrap()
+                                    .virtual_desktop_targets
+                                    .is_empty()
+                                {
+                                    continue;
+                                }
+
+                                let Some(id) = current_virtual_desktop_id() else {
+                                    continue;
+                                };
+                                let mut last = state_ref.last_desktop_id.lock().unwrap();
+                                if last.as_deref() != Some(id.as_str()) {
+                                    let switched = last.is_some();
+                                    *last = Some(id);
+                                    if switched {
+                                        request_advance(&state_ref);
+                                    }
+                                }
+                            }
+                        });
+                    }
+
                     let app_handle = app_handle.clone();
 
                     tauri::async_runtime::spawn(async move {
+                        // auto_quit_counts_paused = false のときに差し引く一時停止中の時間
+                        let mut paused_for = Duration::ZERO;
+                        let mut last_tick = Instant::now();
+                        // 前回の should_run の結果（変わったときだけ schedule-state-changed を送る）
+                        let mut last_schedule_state: Option<bool> = None;
+
                         loop {
                             // --- 設定を読み出す ---
+                            let state_ref = app_handle.state::<AppState>();
+
+                            // 起こされた理由（切替の要求・間隔の変更）は毎回取り出しておく
+                            let advance_requested =
+                                state_ref.advance_requested.swap(false, Ordering::SeqCst);
+                            let reschedule_requested =
+                                state_ref.reschedule_requested.swap(false, Ordering::SeqCst);
+
+                            // 現在の動作状態を先読み（should_run の判定に使う）
+                            let currently_active = state_ref.rotation.lock().unwrap().random_active;
+
+                            let file_targets = effective_targets(&state_ref);
+                            if !file_targets.is_empty() {
+                                state_ref.rotation.lock().unwrap().fallback_shown = false;
+                            }
+
+                            // config の取り出し
                             let (
-                                should_run_now,
-                                file_targets,
-                                initial_wallpaper,
+                                start_dt,
+                                end_dt,
+                                dnd_start,
+                                dnd_end,
+                                weekly,
+                                monthly,
+                                monthly_clamp,
+                                monthly_patterns,
+                                latitude,
+                                longitude,
                                 interval_secs,
+                                interval_jitter_secs,
                                 random_flag,
+                                daily_mode,
+                                random_seed,
+                                auto_rotate,
+                                paused,
+                                cron_schedule,
+                                restore_outside_schedule,
+                                pause_when_fullscreen,
+                                battery_interval_multiplier,
+                                auto_quit_after_secs,
+                                auto_quit_counts_paused,
                             ) = {
-                                let state_ref = app_handle.state::<AppState>();
+                                let cfg = state_ref.config.lock().unwrap();
+                                (
+                                    cfg.start_dt.clone(),
+                                    cfg.end_dt.clone(),
+                                    cfg.dnd_start.clone(),
+                                    cfg.dnd_end.clone(),
+                                    cfg.weekly.clone(),
+                                    cfg.monthly.clone(),
+                                    cfg.monthly_clamp,
+                                    cfg.monthly_patterns.clone(),
+                                    cfg.latitude,
+                                    cfg.longitude,
+                                    effective_interval_secs(&cfg),
+                                    cfg.interval_jitter_secs.unwrap_or(0),
+                                    effective_random(&cfg),
+                                    cfg.daily_mode,
+                                    cfg.random_seed,
+                                    cfg.auto_rotate,
+                                    cfg.paused,
+                                    cfg.cron.as_deref().and_then(|expr| {
+                                        Schedule::from_str(expr)
+                                            .map_err(|e| warn!("invalid cron expression: {e}"))
+                                            .ok()
+                                    }),
+                                    cfg.restore_outside_schedule,
+                                    cfg.pause_when_fullscreen,
+                                    cfg.battery_interval_multiplier,
+                                    cfg.auto_quit_after_secs,
+                                    cfg.auto_quit_counts_paused,
+                                )
+                            };
 
-                                // 現在の動作状態を先読み（should_run の判定に使う）
-                                let currently_active = *state_ref.random_active.lock().unwrap();
-
-                                // config の取り出し
-                                let cfg_cloned = {
-                                    let cfg = state_ref.config.lock().unwrap();
-                                    (
-                                        cfg.file_targets.clone(),
-                                        cfg.start_dt.clone(),
-                                        cfg.end_dt.clone(),
-                                        cfg.weekly.clone(),
-                                        cfg.monthly.clone(),
-                                        if cfg.interval == 0 { 60 } else { cfg.interval },
-                                        cfg.random,
-                                    )
-                                };
+                            // 指定時間を過ぎたら終了時と同じく壁紙を戻して終了する
+                            if paused {
+                                paused_for += last_tick.elapsed();
+                            }
+                            last_tick = Instant::now();
+                            if let Some(limit) = auto_quit_after_secs {
+                                let mut ran_for = state_ref.started_at.elapsed();
+                                if !auto_quit_counts_paused {
+                                    ran_for = ran_for.saturating_sub(paused_for);
+                                }
+                                if ran_for >= Duration::from_secs(limit) {
+                                    restore_on_exit(&app_handle);
+                                    app_handle.exit(0);
+                                    break;
+                                }
+                            }
+                            // 電源状態の確認は config のロック外で行う
+                            let interval_secs =
+                                power_adjusted_interval(interval_secs, battery_interval_multiplier);
 
-                                let (
-                                    file_targets,
+                            // should_run 判定
+                            let (should_run_now, dnd) = {
+                                let tmp_cfg = AppConfig {
                                     start_dt,
                                     end_dt,
+                                    dnd_start,
+                                    dnd_end,
                                     weekly,
                                     monthly,
-                                    interval_secs,
-                                    random_flag,
-                                ) = cfg_cloned;
-
-                                // should_run 判定
-                                let now = Local::now();
-                                let run = {
-                                    let mut tmp_cfg = AppConfig::default();
-                                    tmp_cfg.file_targets = file_targets.clone();
-                                    tmp_cfg.start_dt = start_dt;
-                                    tmp_cfg.end_dt = end_dt;
-                                    tmp_cfg.weekly = weekly;
-                                    tmp_cfg.monthly = monthly;
-                                    tmp_cfg.interval = interval_secs;
-                                    should_run(now, &tmp_cfg, currently_active)
-                                };
-
-                                // initial_wallpaper の取り出し
-                                let initial_wallpaper = {
-                                    let lock = state_ref.initial_wallpaper.lock().unwrap();
-                                    lock.clone()
+                                    monthly_clamp,
+                                    monthly_patterns,
+                                    latitude,
+                                    longitude,
+                                    interval: interval_secs,
+                                    ..AppConfig::default()
                                 };
-
                                 (
-                                    run,
-                                    file_targets,
-                                    initial_wallpaper,
-                                    interval_secs,
-                                    random_flag,
+                                    should_run(Local::now(), &tmp_cfg, currently_active),
+                                    in_dnd_window(Local::now().time(), &tmp_cfg),
                                 )
                             };
 
+                            if last_schedule_state != Some(should_run_now) {
+                                last_schedule_state = Some(should_run_now);
+                                let _ = app_handle.emit("schedule-state-changed", should_run_now);
+                            }
+
                             // --- ランダム / 逐次処理 ---
-                            let state_ref = app_handle.state::<AppState>();
+                            let now = Local::now();
+                            // スヌーズ中か（過ぎていたら解除する）
+                            let snooze_until = {
+                                let mut snooze = state_ref.snooze_until.lock().unwrap();
+                                if snooze.is_some_and(|until| now >= until) {
+                                    *snooze = None;
+                                }
+                                *snooze
+                            };
+                            // 全画面アプリの終了を待つ間は切替予定を残したまま短い間隔で確認する
+                            let fullscreen =
+                                auto_rotate && !paused && pause_when_fullscreen && fullscreen_app_active();
 
-                            if file_targets.is_empty() {
-                                let mut active = state_ref.random_active.lock().unwrap();
-                                if *active {
-                                    if let Some(path) = initial_wallpaper.clone() {
-                                        set_wallpaper(&path);
+                            if !auto_rotate || paused {
+                                // 手動モード / 一時停止中: 自動では一切切り替えない（next/prev コマンドと終了時の復元のみ）
+                                set_next_change(&app_handle, None);
+                            } else if fullscreen {
+                                // 全画面アプリの前面表示中はこの回の切替（復元も含む）を見送る
+                            } else if snooze_until.is_some() {
+                                // スヌーズ中は切替・復元をしない（明けたら予定どおり切り替える）
+                            } else if dnd {
+                                // DND 中は cron も含めて切替・復元をしない（予定は残して明けたら切り替える）
+                            } else if file_targets.is_empty() {
+                                let was_active = std::mem::replace(
+                                    &mut state_ref.rotation.lock().unwrap().random_active,
+                                    false,
+                                );
+                                let fallback_shown =
+                                    state_ref.rotation.lock().unwrap().fallback_shown;
+                                if was_active && restore_outside_schedule {
+                                    restore_blocking(&app_handle).await;
+                                } else if !fallback_shown && restore_target(&state_ref).is_none() {
+                                    // 起動時から対象も戻す壁紙も無いときは fallback_color を表示する
+                                    if let Some(path) = fallback_wallpaper(&state_ref) {
+                                        apply_and_emit_blocking(&app_handle, path, None, false, "restore")
+                                            .await;
                                     }
-                                    *active = false;
+                                    state_ref.rotation.lock().unwrap().fallback_shown = true;
                                 }
                                 // clear index and last_shown when no targets
-                                let mut idx_lock = state_ref.current_index.lock().unwrap();
-                                *idx_lock = None;
-                                let mut last_shown_lock = state_ref.last_shown.lock().unwrap();
-                                *last_shown_lock = None;
-                            } else {
-                                let mut active = state_ref.random_active.lock().unwrap();
-
-                                // grab tracking locks we need
-                                let mut last_rand = state_ref.last_random_enabled.lock().unwrap();
-                                let mut idx_lock = state_ref.current_index.lock().unwrap();
-                                let mut last_shown_lock = state_ref.last_shown.lock().unwrap();
-
-                                if should_run_now {
-                                    *active = true;
-
-                                    if random_flag {
-                                        // random mode: pick randomly and remember last shown; clear sequential index
-                                        let mut rng = thread_rng();
-                                        if let Some(choice) = file_targets.choose(&mut rng) {
-                                            set_wallpaper(choice);
-                                            *last_shown_lock = Some(choice.clone());
-                                        }
-                                        *idx_lock = None;
-                                        *last_rand = true;
-                                    } else {
-                                        // sequential mode: if we just toggled from random -> sequential,
-                                        // start from the next index after the last shown image
-                                        if *last_rand && idx_lock.is_none() {
-                                            if let Some(last_path) = &*last_shown_lock {
-                                                if let Some(pos) =
-                                                    file_targets.iter().position(|p| p == last_path)
-                                                {
-                                                    *idx_lock =
-                                                        Some((pos + 1) % file_targets.len());
-                                                } else {
-                                                    *idx_lock = Some(0);
-                                                }
-                                            } else if let Some(current) = get_current_wallpaper() {
-                                                if let Some(pos) =
-                                                    file_targets.iter().position(|p| p == &current)
-                                                {
-                                                    *idx_lock =
-                                                        Some((pos + 1) % file_targets.len());
-                                                } else {
-                                                    *idx_lock = Some(0);
-                                                }
-                                            } else {
-                                                *idx_lock = Some(0);
-                                            }
-                                        }
-
-                                        // update remembered flag: we're now in sequential mode
-                                        *last_rand = false;
+                                {
+                                    let mut rotation = state_ref.rotation.lock().unwrap();
+                                    rotation.current_index = None;
+                                    rotation.last_shown = None;
+                                }
+                                set_next_change(&app_handle, None);
+                            } else if let Some(schedule) = &cron_schedule {
+                                // cron 指定時は実行時刻になったときだけ切り替える（should_run / interval は使わない）
+                                let due = state_ref
+                                    .next_change_at
+                                    .lock()
+                                    .unwrap()
+                                    .is_some_and(|at| now >= at);
+                                if due {
+                                    let (shown, outcomes) = step_wallpaper_blocking(
+                                        &app_handle,
+                                        file_targets.clone(),
+                                        random_flag,
+                                    )
+                                    .await;
+                                    emit_outcomes(&app_handle, &outcomes);
+                                    if shown.is_some() {
+                                        state_ref.rotation.lock().unwrap().random_active = true;
+                                    }
+                                }
+                                set_next_change(&app_handle, schedule.after(&now).next());
+                            } else if should_run_now {
+                                let was_active = std::mem::replace(
+                                    &mut state_ref.rotation.lock().unwrap().random_active,
+                                    true,
+                                );
 
-                                        // sequential: ensure we have an index and show it
-                                        if idx_lock.is_none() {
-                                            *idx_lock = Some(0);
-                                        }
-                                        if let Some(i) = *idx_lock {
-                                            let path = &file_targets[i % file_targets.len()];
-                                            set_wallpaper(path);
-                                            *last_shown_lock = Some(path.clone());
-                                            *idx_lock = Some((i + 1) % file_targets.len());
+                                if daily_mode {
+                                    // daily mode: only switch when the date changed (or we just
+                                    // (re)entered the run window and restored the initial meanwhile)
+                                    let today = now.date_naive();
+                                    let last_daily = *state_ref.last_daily_date.lock().unwrap();
+                                    if !was_active || last_daily != Some(today) {
+                                        if let Some(choice) = daily_pick(
+                                            &file_targets,
+                                            today,
+                                            random_flag,
+                                            random_seed,
+                                        )
+                                        .cloned()
+                                        {
+                                            let index =
+                                                file_targets.iter().position(|p| *p == choice);
+                                            apply_and_emit_blocking(
+                                                &app_handle,
+                                                choice.clone(),
+                                                index,
+                                                random_flag,
+                                                "daily",
+                                            )
+                                            .await;
+                                            state_ref.rotation.lock().unwrap().last_shown = Some(choice);
                                         }
+                                        *state_ref.last_daily_date.lock().unwrap() = Some(today);
+                                    }
+                                    {
+                                        let mut rotation = state_ref.rotation.lock().unwrap();
+                                        rotation.current_index = None;
+                                        rotation.last_random_enabled = random_flag;
                                     }
+                                    set_next_change(&app_handle, next_midnight(now));
                                 } else {
-                                    if *active {
-                                        if let Some(path) = initial_wallpaper.clone() {
-                                            set_wallpaper(&path);
-                                        }
-                                        *active = false;
+                                    let due = interval_change_due(
+                                        &state_ref,
+                                        now,
+                                        advance_requested,
+                                        was_active,
+                                    );
+                                    if due {
+                                        let (_, outcomes) = step_wallpaper_blocking(
+                                            &app_handle,
+                                            file_targets.clone(),
+                                            random_flag,
+                                        )
+                                        .await;
+                                        emit_outcomes(&app_handle, &outcomes);
+                                    }
+                                    if due || reschedule_requested {
+                                        let at = next_interval_change(
+                                            &state_ref,
+                                            now,
+                                            interval_secs,
+                                            interval_jitter_secs,
+                                        );
+                                        set_next_change(&app_handle, Some(at));
                                     }
                                 }
+                            } else {
+                                let was_active = std::mem::replace(
+                                    &mut state_ref.rotation.lock().unwrap().random_active,
+                                    false,
+                                );
+                                if was_active && restore_outside_schedule {
+                                    restore_blocking(&app_handle).await;
+                                }
+                                set_next_change(&app_handle, None);
                             }
+                            update_tray(&app_handle);
 
-                            // 最大60秒ごとに時刻を再チェック（開始・終了の検出遅延を60秒以内に抑える）
-                            let sleep_secs = interval_secs.min(60);
+                            // 次の切替予定まで、ただし最大60秒ごとに時刻を再チェック
+                            // （開始・終了の検出遅延を60秒以内に抑える）
+                            let until_next =
+                                remaining_secs(*state_ref.next_change_at.lock().unwrap());
+                            let sleep_secs = if fullscreen {
+                                FULLSCREEN_RECHECK_SECS
+                            } else if let Some(secs) = remaining_secs(snooze_until) {
+                                // スヌーズが明けたらすぐ起きる
+                                secs.clamp(1, 60)
+                            } else if dnd && auto_rotate && !paused {
+                                60
+                            } else {
+                                until_next.unwrap_or(interval_secs).clamp(1, 60)
+                            };
                             tokio::select! {
-                                _ = sleep(Duration::from_secs(sleep_secs)) => {},
-                                _ = state_ref.notify.notified() => {},
+                                _ = sleep(Duration::from_secs(sleep_secs)) => {}
+                                _ = state_ref.notify.notified() => {}
                             }
                         }
                     });
                 }
 
-                RunEvent::ExitRequested { .. } => {
+                RunEvent::ExitRequested { code, api, .. } => {
+                    // ヘッドレスではウィンドウが無いので、ウィンドウが全て閉じたことによる終了要求
+                    // （code が None）は無視してトレイから終了するまで動き続ける
+                    let headless = app_handle
+                        .try_state::<AppState>()
+                        .is_some_and(|state| state.config.lock().unwrap().headless);
+                    if headless && code.is_none() {
+                        api.prevent_exit();
+                        return;
+                    }
                     // 終了時に壁紙を戻す処理
-                    let state_ref = app_handle.state::<AppState>();
-                    let initial = state_ref.initial_wallpaper.lock().unwrap().clone();
-                    if let Some(path) = initial {
-                        set_wallpaper(&path);
+                    restore_on_exit(app_handle);
+                }
+
+                RunEvent::Exit => {
+                    // 最後の書き出し以降の表示回数と設定の変更を残す
+                    let state = app_handle.state::<AppState>();
+                    flush_stats(&state);
+                    if let Err(e) = flush_config(&state) {
+                        error!("failed to save config: {e}");
                     }
                 }
 
@@ -731,3 +5603,1027 @@ fn main() {
             }
         });
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+    use std::sync::atomic::AtomicUsize;
+
+    fn utc(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn date(y: i32, m: u32, d: u32) -> NaiveDate {
+        NaiveDate::from_ymd_opt(y, m, d).unwrap()
+    }
+
+    fn local(y: i32, m: u32, d: u32, h: u32, min: u32) -> DateTime<Local> {
+        Local.with_ymd_and_hms(y, m, d, h, min, 0).unwrap()
+    }
+
+    fn hhmm(h: u32, min: u32) -> NaiveTime {
+        NaiveTime::from_hms_opt(h, min, 0).unwrap()
+    }
+
+    fn assert_near(actual: DateTime<Utc>, expected: DateTime<Utc>) {
+        let diff = (actual - expected).num_minutes().abs();
+        assert!(diff <= 3, "{actual} is {diff} minutes off {expected}");
+    }
+
+    // set の呼び出しを記録し、failing の画像と最初の transient_failures 回だけ失敗させる
+    #[derive(Default)]
+    struct MockSetter {
+        sets: Mutex<Vec<PathBuf>>,
+        failing: HashSet<PathBuf>,
+        transient_failures: AtomicUsize,
+        showing: Mutex<Option<PathBuf>>,
+    }
+
+    impl WallpaperSetter for MockSetter {
+        fn set(&self, p: &Path) -> Result<(), String> {
+            self.sets.lock().unwrap().push(p.to_path_buf());
+            let transient = self
+                .transient_failures
+                .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| n.checked_sub(1))
+                .is_ok();
+            if transient || self.failing.contains(p) {
+                return Err("mock failure".to_string());
+            }
+            *self.showing.lock().unwrap() = Some(p.to_path_buf());
+            Ok(())
+        }
+
+        fn get(&self) -> Option<PathBuf> {
+            self.showing.lock().unwrap().clone()
+        }
+    }
+
+    fn test_state(cfg: AppConfig) -> AppState {
+        AppState::new(None, cfg, WindowState::default())
+    }
+
+    fn paths(names: &[&str]) -> Vec<PathBuf> {
+        names.iter().map(PathBuf::from).collect()
+    }
+
+    // テストごとの空の作業フォルダー
+    fn temp_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!(
+            "wallpaper_changer_test_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    // dir の下に空のファイルを作る（途中のフォルダーも作る）
+    fn touch(dir: &Path, files: &[&str]) {
+        for file in files {
+            let path = dir.join(file);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, b"").unwrap();
+        }
+    }
+
+    // dir からの相対パスにして並べる（read_dir の順番は環境によって違う）
+    fn relative_sorted(dir: &Path, mut files: Vec<PathBuf>) -> Vec<PathBuf> {
+        files.sort();
+        files
+            .into_iter()
+            .map(|f| f.strip_prefix(dir).unwrap().to_path_buf())
+            .collect()
+    }
+
+    #[test]
+    fn sun_times_matches_known_sunrise_and_sunset() {
+        // 東京の夏至: 日の出 4:25 / 日の入り 19:00 (JST)
+        let SunTimes::Daylight(rise, set) = sun_times(date(2024, 6, 21), 35.6895, 139.6917) else {
+            panic!("expected daylight in Tokyo");
+        };
+        assert_near(rise, utc(2024, 6, 20, 19, 25));
+        assert_near(set, utc(2024, 6, 21, 10, 0));
+
+        // ロンドンの春分: 日の出 6:03 / 日の入り 18:13 (UTC)
+        let SunTimes::Daylight(rise, set) = sun_times(date(2024, 3, 20), 51.5074, -0.1278) else {
+            panic!("expected daylight in London");
+        };
+        assert_near(rise, utc(2024, 3, 20, 6, 3));
+        assert_near(set, utc(2024, 3, 20, 18, 13));
+    }
+
+    #[test]
+    fn sun_times_handles_polar_day_and_night() {
+        // ロングイェールビーン（北緯78度）は夏至に白夜、冬至に極夜
+        assert_eq!(
+            sun_times(date(2024, 6, 21), 78.22, 15.65),
+            SunTimes::AlwaysDay
+        );
+        assert_eq!(
+            sun_times(date(2024, 12, 21), 78.22, 15.65),
+            SunTimes::AlwaysNight
+        );
+        // 南半球は逆になる
+        assert_eq!(
+            sun_times(date(2024, 6, 21), -80.0, 0.0),
+            SunTimes::AlwaysNight
+        );
+    }
+
+    #[test]
+    fn should_run_follows_daylight_when_location_is_set() {
+        let cfg = AppConfig {
+            latitude: Some(78.22),
+            longitude: Some(15.65),
+            // 位置があれば start_dt / end_dt は使わない
+            start_dt: Some("00:00".to_string()),
+            end_dt: Some("00:01".to_string()),
+            ..AppConfig::default()
+        };
+        let summer = utc(2024, 6, 21, 12, 0).with_timezone(&Local);
+        let winter = utc(2024, 12, 21, 12, 0).with_timezone(&Local);
+        assert!(should_run(summer, &cfg, false));
+        assert!(!should_run(winter, &cfg, false));
+    }
+
+    #[test]
+    fn same_seed_picks_the_same_sequence() {
+        let targets = paths(&["a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg"]);
+        let picks = |seed| {
+            let mut rng = make_rng(Some(seed));
+            (0..20)
+                .map(|_| {
+                    pick_random(
+                        &targets,
+                        &[],
+                        &VecDeque::new(),
+                        &HashMap::new(),
+                        false,
+                        &mut rng,
+                    )
+                    .unwrap()
+                })
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(picks(42), picks(42));
+        assert_ne!(picks(42), picks(7));
+    }
+
+    #[test]
+    fn retry_with_backoff_recovers_from_transient_failures() {
+        let setter = MockSetter {
+            transient_failures: AtomicUsize::new(SET_RETRY_DELAYS_MS.len()),
+            ..MockSetter::default()
+        };
+        let path = Path::new("a.jpg");
+        assert!(retry_with_backoff(|| setter.set(path)).is_ok());
+        assert_eq!(
+            setter.sets.lock().unwrap().len(),
+            SET_RETRY_DELAYS_MS.len() + 1
+        );
+        assert_eq!(setter.get(), Some(path.to_path_buf()));
+    }
+
+    #[test]
+    fn retry_with_backoff_returns_the_last_error() {
+        let setter = MockSetter {
+            transient_failures: AtomicUsize::new(SET_RETRY_DELAYS_MS.len() + 1),
+            ..MockSetter::default()
+        };
+        let result = retry_with_backoff(|| setter.set(Path::new("a.jpg")));
+        assert_eq!(result, Err("mock failure".to_string()));
+        assert_eq!(
+            setter.sets.lock().unwrap().len(),
+            SET_RETRY_DELAYS_MS.len() + 1
+        );
+        assert_eq!(setter.get(), None);
+    }
+
+    #[test]
+    fn step_wallpaper_walks_targets_in_order() {
+        let setter = MockSetter::default();
+        let state = test_state(AppConfig::default());
+        let targets = paths(&["a.jpg", "b.jpg", "c.jpg"]);
+        for _ in 0..4 {
+            let (shown, outcomes) = step_wallpaper(&setter, &state, &targets, false, true);
+            assert!(shown.is_some());
+            assert_eq!(outcomes.len(), 1);
+        }
+        assert_eq!(
+            *setter.sets.lock().unwrap(),
+            paths(&["a.jpg", "b.jpg", "c.jpg", "a.jpg"])
+        );
+        let rotation = state.rotation.lock().unwrap();
+        assert_eq!(rotation.current_index, Some(1));
+        assert_eq!(rotation.last_shown, Some(PathBuf::from("a.jpg")));
+        assert_eq!(state.history_log.lock().unwrap().len(), 4);
+    }
+
+    #[test]
+    fn select_and_set_moves_past_a_failing_image() {
+        let setter = MockSetter {
+            failing: HashSet::from([PathBuf::from("a.jpg")]),
+            ..MockSetter::default()
+        };
+        let state = test_state(AppConfig::default());
+        let targets = paths(&["a.jpg", "b.jpg"]);
+        let mut pos = RotationState::default();
+        let (shown, outcomes) = select_and_set(&setter, &state, &targets, false, true, &mut pos);
+        assert_eq!(shown, Some(PathBuf::from("b.jpg")));
+        assert!(matches!(&outcomes[..], [Err(e), Ok(_)] if e.path == "a.jpg" && !e.skipped));
+        // 再試行の分も a.jpg を設定しようとしている
+        let sets = setter.sets.lock().unwrap();
+        assert_eq!(sets.len(), 1 + SET_RETRY_DELAYS_MS.len() + 1);
+        assert_eq!(pos.current_index, Some(0));
+        assert!(state.last_error.lock().unwrap().is_some());
+    }
+
+    #[test]
+    fn select_and_set_gives_up_after_too_many_failures() {
+        let targets = paths(&["a.jpg", "b.jpg", "c.jpg", "d.jpg", "e.jpg"]);
+        let setter = MockSetter {
+            failing: targets.iter().cloned().collect(),
+            ..MockSetter::default()
+        };
+        let state = test_state(AppConfig::default());
+        let mut pos = RotationState::default();
+        let (shown, outcomes) = select_and_set(&setter, &state, &targets, false, true, &mut pos);
+        assert_eq!(shown, None);
+        assert_eq!(outcomes.len(), MAX_FAILURES_PER_STEP);
+        assert!(state.history_log.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn weekly_validation_reports_only_the_bad_entries() {
+        let weekly: Vec<String> = ["mon", "funday", "Fri", "8", "日"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        assert_eq!(invalid_weekdays(&weekly), ["funday", "8"]);
+        let cfg = AppConfig {
+            weekly: Some(weekly),
+            ..AppConfig::default()
+        };
+        assert_eq!(config_errors(&cfg), ["unknown weekday: funday, 8"]);
+    }
+
+    #[test]
+    fn weekday_names_accept_full_abbreviated_and_japanese_forms() {
+        for (names, expected) in [
+            (&["Sunday", "sun", "日"][..], Weekday::Sun),
+            (&["MONDAY", "Mon", "月"], Weekday::Mon),
+            (&["tuesday", "tue", "tues", "火"], Weekday::Tue),
+            (&["wednesday", "wed", "水"], Weekday::Wed),
+            (&["thursday", "thu", "thur", "thurs", "木"], Weekday::Thu),
+            (&["friday", "fri", "金"], Weekday::Fri),
+            (&["saturday", "sat", "土"], Weekday::Sat),
+        ] {
+            for name in names {
+                assert_eq!(weekday_str_to_enum(name), Some(expected), "{name}");
+            }
+        }
+        for name in ["mo", "月曜", "日曜日", "sundays"] {
+            assert_eq!(weekday_str_to_enum(name), None, "{name}");
+        }
+    }
+
+    #[test]
+    fn numeric_weekdays_start_on_sunday() {
+        let expected = [
+            Weekday::Sun,
+            Weekday::Mon,
+            Weekday::Tue,
+            Weekday::Wed,
+            Weekday::Thu,
+            Weekday::Fri,
+            Weekday::Sat,
+        ];
+        for (n, weekday) in expected.into_iter().enumerate() {
+            assert_eq!(weekday_str_to_enum(&n.to_string()), Some(weekday));
+        }
+        for name in ["7", "-1", "01"] {
+            assert_eq!(weekday_str_to_enum(name), None, "{name}");
+        }
+
+        // 2024-05-05 は日曜日
+        let cfg = AppConfig {
+            weekly: Some(vec!["0".to_string()]),
+            ..AppConfig::default()
+        };
+        assert!(should_run(local(2024, 5, 5, 12, 0), &cfg, false));
+        assert!(!should_run(local(2024, 5, 6, 12, 0), &cfg, false));
+    }
+
+    #[test]
+    fn monthly_clamp_moves_day_31_to_the_end_of_short_months() {
+        let cfg = AppConfig {
+            monthly: Some(vec![31]),
+            ..AppConfig::default()
+        };
+        // 4 月は 30 日まで
+        assert!(!should_run(local(2024, 4, 30, 12, 0), &cfg, false));
+        assert!(should_run(local(2024, 5, 31, 12, 0), &cfg, false));
+
+        let cfg = AppConfig {
+            monthly_clamp: true,
+            ..cfg
+        };
+        assert!(should_run(local(2024, 4, 30, 12, 0), &cfg, false));
+        assert!(!should_run(local(2024, 4, 29, 12, 0), &cfg, false));
+        // 閏年の 2 月は 29 日、平年は 28 日
+        assert!(should_run(local(2024, 2, 29, 12, 0), &cfg, false));
+        assert!(should_run(local(2023, 2, 28, 12, 0), &cfg, false));
+        assert!(!should_run(local(2024, 2, 28, 12, 0), &cfg, false));
+        // 31 日まである月はそのまま
+        assert!(!should_run(local(2024, 5, 30, 12, 0), &cfg, false));
+        assert!(should_run(local(2024, 5, 31, 12, 0), &cfg, false));
+    }
+
+    #[test]
+    fn month_patterns_match_across_month_lengths() {
+        let matches = |y, m, d, pattern| matches_month_pattern(local(y, m, d, 12, 0), pattern);
+
+        // 月末: 30 日の月、31 日の月、閏年と平年の 2 月
+        assert!(matches(2024, 4, 30, "last"));
+        assert!(!matches(2024, 5, 30, "last"));
+        assert!(matches(2024, 5, 31, "last"));
+        assert!(matches(2024, 2, 29, "last"));
+        assert!(!matches(2024, 2, 28, "last"));
+        assert!(matches(2023, 2, 28, "last"));
+
+        // 月最後の平日: 2024-03-31 は日曜なので 29 日（金）、2024-02-29 は木曜
+        assert!(matches(2024, 3, 29, "last-weekday"));
+        assert!(!matches(2024, 3, 31, "last-weekday"));
+        assert!(matches(2024, 2, 29, "last-weekday"));
+        // 2023-09-30 は土曜なので 29 日（金）
+        assert!(matches(2023, 9, 29, "last-weekday"));
+
+        // 月最後の金曜日: 2024-05 は 31 日、2024-02 は 23 日
+        assert!(matches(2024, 5, 31, "last-fri"));
+        assert!(!matches(2024, 5, 24, "last-fri"));
+        assert!(matches(2024, 2, 23, "last-fri"));
+
+        // 第 n 曜日: 2024-05 の月曜は 6, 13, 20, 27 日。第 5 月曜は無い
+        assert!(matches(2024, 5, 6, "1st-mon"));
+        assert!(matches(2024, 5, 20, "3rd-mon"));
+        assert!(!matches(2024, 5, 27, "3rd-mon"));
+        assert!(!(1..=31).any(|d| matches(2024, 5, d, "5th-mon")));
+        // 閏年の 2 月は第 5 木曜（29 日）がある
+        assert!(matches(2024, 2, 29, "5th-thu"));
+        assert!(matches(2024, 2, 29, "5th-木"));
+
+        assert!(!matches(2024, 5, 31, "6th-fri"));
+        assert!(!matches(2024, 5, 31, "last-funday"));
+    }
+
+    #[test]
+    fn jittered_interval_stays_within_jitter() {
+        let mut rng = make_rng(Some(1));
+        let waits: Vec<u64> = (0..1000)
+            .map(|_| jittered_interval(60, 10, &mut rng))
+            .collect();
+        assert!(waits.iter().all(|w| (50..=70).contains(w)));
+        // 両端まで出る
+        assert!(waits.contains(&50) && waits.contains(&70));
+
+        assert_eq!(jittered_interval(60, 0, &mut rng), 60);
+        // 揺らしても 1 秒未満にはならない
+        assert!((0..1000).all(|_| jittered_interval(5, 30, &mut rng) >= 1));
+        assert_eq!(jittered_interval(0, 0, &mut rng), 1);
+    }
+
+    #[test]
+    fn config_round_trips_non_ascii_paths() {
+        let dir = temp_dir("壁紙_non_ascii");
+        let cfg = AppConfig {
+            file_targets: vec![dir.join("夏の海.jpg"), dir.join("Ünïcödé 🌄.png")],
+            folder_targets: vec![dir.join("写真")],
+            exit_wallpaper_path: Some(dir.join("終了時.jpg")),
+            ..AppConfig::default()
+        };
+        write_config_file(&dir, &cfg).unwrap();
+        let path = config_path(&dir);
+        let loaded = parse_config(&path, &fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(loaded, cfg);
+        // UTF-8 にできる短いパスはそのまま設定できる
+        assert!(cfg.file_targets.iter().all(|p| !needs_short_path(p)));
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn non_utf8_paths_need_a_short_copy() {
+        use std::os::unix::ffi::OsStrExt;
+        let path = Path::new(std::ffi::OsStr::from_bytes(b"/tmp/\xff.jpg"));
+        assert!(needs_short_path(path));
+    }
+
+    #[test]
+    fn active_playlist_overrides_targets_interval_and_random() {
+        let playlist = Playlist {
+            targets: paths(&["p1.jpg", "p2.jpg"]),
+            interval: Some(5),
+            random: Some(false),
+        };
+        let cfg = AppConfig {
+            file_targets: paths(&["a.jpg"]),
+            interval: 30,
+            interval_unit: Some("minutes".to_string()),
+            random: true,
+            playlists: HashMap::from([("work".to_string(), playlist)]),
+            active_playlist: Some("work".to_string()),
+            time_collections: Some(HashMap::from([(
+                "morning".to_string(),
+                paths(&["morning.jpg"]),
+            )])),
+            virtual_desktop_targets: HashMap::from([("desk-2".to_string(), paths(&["desk.jpg"]))]),
+            ..AppConfig::default()
+        };
+        assert_eq!(effective_interval_secs(&cfg), 5 * 60);
+        assert!(!effective_random(&cfg));
+        let state = test_state(cfg.clone());
+        // プレイリストは時間帯用の画像より優先し、仮想デスクトップ用の画像には負ける
+        let (targets, _) = build_effective_targets(&state, None, Some("morning"));
+        assert_eq!(targets, paths(&["p1.jpg", "p2.jpg"]));
+        let (targets, _) = build_effective_targets(&state, Some("desk-2"), Some("morning"));
+        assert_eq!(targets, paths(&["desk.jpg"]));
+
+        // 削除済みのプレイリスト名なら通常の設定に戻る
+        let state = test_state(AppConfig {
+            active_playlist: Some("gone".to_string()),
+            ..cfg
+        });
+        let cfg = state.config.lock().unwrap().clone();
+        assert_eq!(effective_interval_secs(&cfg), 30 * 60);
+        assert!(effective_random(&cfg));
+        let (targets, _) = build_effective_targets(&state, None, Some("morning"));
+        assert_eq!(targets, paths(&["morning.jpg"]));
+        let (targets, _) = build_effective_targets(&state, None, None);
+        assert_eq!(targets, paths(&["a.jpg"]));
+    }
+
+    #[test]
+    fn pick_random_avoids_recent_picks() {
+        let targets = paths(&["a.jpg", "b.jpg", "c.jpg"]);
+        let recent: VecDeque<PathBuf> = paths(&["a.jpg", "b.jpg"]).into();
+        let mut rng = make_rng(Some(3));
+        for _ in 0..50 {
+            let picked = pick_random(&targets, &[], &recent, &HashMap::new(), false, &mut rng);
+            assert_eq!(picked, Some(2));
+        }
+        // 避けたい画像しか残っていなければ避けない
+        let picked = pick_random(&targets, &[2], &recent, &HashMap::new(), false, &mut rng);
+        assert!(matches!(picked, Some(0 | 1)));
+        assert_eq!(
+            pick_random(
+                &targets,
+                &[0, 1, 2],
+                &recent,
+                &HashMap::new(),
+                false,
+                &mut rng
+            ),
+            None
+        );
+
+        // 切替を続けても直近 2 回に出した画像は選ばれない
+        let setter = MockSetter::default();
+        let state = test_state(AppConfig {
+            recent_avoid: Some(2),
+            random_seed: Some(3),
+            ..AppConfig::default()
+        });
+        let shown: Vec<PathBuf> = (0..12)
+            .filter_map(|_| step_wallpaper(&setter, &state, &targets, true, true).0)
+            .collect();
+        assert_eq!(shown.len(), 12);
+        assert!(shown
+            .windows(3)
+            .all(|w| w[0] != w[1] && w[1] != w[2] && w[0] != w[2]));
+    }
+
+    #[test]
+    fn sequential_step_pingpong_bounces_at_both_ends() {
+        let mut reverse = false;
+        let mut i = 0;
+        let mut order = vec![i];
+        for _ in 0..8 {
+            i = sequential_step(i, 4, "pingpong", &mut reverse, false);
+            order.push(i);
+        }
+        assert_eq!(order, [0, 1, 2, 3, 2, 1, 0, 1, 2]);
+
+        // 前へ戻すと来た道を引き返す（向きは端で折り返すときだけ変わる）
+        let mut reverse = true;
+        assert_eq!(sequential_step(2, 4, "pingpong", &mut reverse, true), 3);
+        assert!(reverse);
+        let mut reverse = false;
+        assert_eq!(sequential_step(1, 4, "pingpong", &mut reverse, true), 0);
+        assert!(!reverse);
+        assert_eq!(sequential_step(0, 4, "pingpong", &mut reverse, true), 1);
+    }
+
+    #[test]
+    fn step_wallpaper_pingpong_over_four_images() {
+        let setter = MockSetter::default();
+        let state = test_state(AppConfig {
+            sequential_direction: Some("pingpong".to_string()),
+            ..AppConfig::default()
+        });
+        let targets = paths(&["a.jpg", "b.jpg", "c.jpg", "d.jpg"]);
+        let shown: Vec<PathBuf> = (0..8)
+            .filter_map(|_| step_wallpaper(&setter, &state, &targets, false, true).0)
+            .collect();
+        assert_eq!(
+            shown,
+            paths(&["a.jpg", "b.jpg", "c.jpg", "d.jpg", "c.jpg", "b.jpg", "a.jpg", "b.jpg"])
+        );
+
+        // 前へは直前に出した画像に戻る
+        let (shown, _) = step_wallpaper(&setter, &state, &targets, false, false);
+        assert_eq!(shown, Some(PathBuf::from("a.jpg")));
+    }
+
+    #[test]
+    fn choose_folder_weighted_picks_folders_evenly() {
+        // 画像 1 枚のフォルダーと 9 枚のフォルダー
+        let mut targets = paths(&["small/only.jpg"]);
+        targets.extend((0..9).map(|i| PathBuf::from(format!("big/{i}.jpg"))));
+        let candidates: Vec<usize> = (0..targets.len()).collect();
+        let mut rng = make_rng(Some(339));
+        let draws = 10_000;
+        let small = (0..draws)
+            .filter(|_| {
+                choose_folder_weighted(&targets, &candidates, &HashMap::new(), &mut rng) == Some(0)
+            })
+            .count();
+        // フォルダー単位で半々（画像単位なら 1 割）
+        assert!(
+            (4_500..=5_500).contains(&small),
+            "small folder picked {small} times"
+        );
+    }
+
+    #[test]
+    fn choose_weighted_image_follows_weights() {
+        let targets = paths(&["heavy.jpg", "light.jpg", "never.jpg"]);
+        let weights = HashMap::from([
+            (PathBuf::from("heavy.jpg"), 10),
+            (PathBuf::from("never.jpg"), 0),
+        ]);
+        let candidates = [0, 1, 2];
+        let mut rng = make_rng(Some(340));
+        let mut counts = [0; 3];
+        for _ in 0..11_000 {
+            let i = choose_weighted_image(&targets, &candidates, &weights, &mut rng).unwrap();
+            counts[i] += 1;
+        }
+        // 重み 10 : 1 なので heavy はおよそ 10_000 回、light はおよそ 1_000 回
+        assert!((9_500..=10_500).contains(&counts[0]), "{counts:?}");
+        assert!((700..=1_300).contains(&counts[1]), "{counts:?}");
+        assert_eq!(counts[2], 0);
+    }
+
+    #[test]
+    fn apply_wallpaper_only_records_in_dry_run() {
+        let setter = MockSetter::default();
+        let state = test_state(AppConfig {
+            dry_run: true,
+            ..AppConfig::default()
+        });
+        let outcome = apply_wallpaper(
+            &setter,
+            &state,
+            Path::new("a.jpg"),
+            None,
+            None,
+            true,
+            "random",
+        );
+        assert!(outcome.is_ok_and(|changed| changed.dry_run));
+        assert!(setter.sets.lock().unwrap().is_empty());
+        assert_eq!(state.history_log.lock().unwrap().len(), 1);
+        assert!(state.show_counts.lock().unwrap().is_empty());
+    }
+
+    #[test]
+    fn migrate_config_upgrades_v1_playlists() {
+        // version の無い v1 の設定ファイル（プレイリストは画像の配列）
+        let v1 = r#"{
+            "interval": 120,
+            "random": false,
+            "fileTargets": ["C:\\Pics\\a.jpg"],
+            "playlists": {
+                "work": ["C:\\Pics\\w1.jpg", "C:\\Pics\\w2.jpg"]
+            },
+            "activePlaylist": "work"
+        }"#;
+        let cfg = parse_config(Path::new("config.json"), v1).unwrap();
+        assert_eq!(cfg.version, CONFIG_VERSION);
+        assert_eq!(cfg.interval, 120);
+        assert!(!cfg.random);
+        assert_eq!(
+            cfg.playlists.get("work"),
+            Some(&Playlist {
+                targets: paths(&["C:\\Pics\\w1.jpg", "C:\\Pics\\w2.jpg"]),
+                interval: None,
+                random: None,
+            })
+        );
+        assert_eq!(cfg.active_playlist.as_deref(), Some("work"));
+    }
+
+    #[test]
+    fn migrate_config_keeps_newer_versions() {
+        let value = serde_json::json!({ "version": CONFIG_VERSION + 1 });
+        assert_eq!(migrate_config(value).unwrap().version, CONFIG_VERSION + 1);
+    }
+
+    #[test]
+    fn config_errors_accepts_the_default_config() {
+        assert!(config_errors(&AppConfig::default()).is_empty());
+    }
+
+    #[test]
+    fn config_errors_reports_every_problem() {
+        let cfg = AppConfig {
+            log_level: Some("loud".to_string()),
+            config_format: Some("yaml".to_string()),
+            latitude: Some(91.0),
+            longitude: Some(0.0),
+            fallback_color: Some("#12345".to_string()),
+            battery_interval_multiplier: Some(0.0),
+            cron: Some("every minute".to_string()),
+            sequential_direction: Some("sideways".to_string()),
+            interval_unit: Some("days".to_string()),
+            monthly: Some(vec![0, 15, 32]),
+            ..AppConfig::default()
+        };
+        let errors = config_errors(&cfg);
+        for expected in [
+            "unknown log level: loud",
+            "unknown config format: yaml",
+            "invalid location: 91, 0",
+            "invalid fallback color: #12345",
+            "invalid battery interval multiplier: 0",
+            "unknown sequential direction: sideways",
+            "unknown interval unit: days",
+            "invalid monthly day: 0, 32",
+        ] {
+            assert!(
+                errors.iter().any(|e| e == expected),
+                "{expected} not in {errors:?}"
+            );
+        }
+        assert!(errors
+            .iter()
+            .any(|e| e.starts_with("invalid cron expression")));
+        assert_eq!(errors.len(), 9, "{errors:?}");
+    }
+
+    #[test]
+    fn config_round_trips_through_toml() {
+        let dir = temp_dir("toml_round_trip");
+        write_config_file(&dir, &AppConfig::default()).unwrap();
+        assert!(dir.join("config.json").exists());
+
+        let cfg = AppConfig {
+            config_format: Some("toml".to_string()),
+            interval: 15,
+            interval_unit: Some("minutes".to_string()),
+            start_dt: Some("08:30".to_string()),
+            file_targets: paths(&["C:\\Pics\\a.jpg", "/home/me/b.png"]),
+            playlists: HashMap::from([(
+                "work".to_string(),
+                Playlist {
+                    targets: paths(&["C:\\Pics\\w.jpg"]),
+                    interval: Some(3),
+                    random: None,
+                },
+            )]),
+            weights: HashMap::from([(PathBuf::from("C:\\Pics\\a.jpg"), 4)]),
+            tags: HashMap::from([(PathBuf::from("/home/me/b.png"), vec!["cats".to_string()])]),
+            latitude: Some(35.5),
+            longitude: Some(139.25),
+            ..AppConfig::default()
+        };
+        write_config_file(&dir, &cfg).unwrap();
+        // 形式を変えたら前の形式のファイルは消える
+        assert!(!dir.join("config.json").exists());
+        let path = config_path(&dir);
+        assert_eq!(path, dir.join("config.toml"));
+        let loaded = parse_config(&path, &fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(loaded, cfg);
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn collect_images_stops_at_max_depth() {
+        let dir = temp_dir("max_depth");
+        touch(
+            &dir,
+            &[
+                "top.jpg",
+                "notes.txt",
+                "a/one.png",
+                "a/b/two.jpg",
+                "a/b/c/three.jpg",
+            ],
+        );
+        let scan = |max_depth| ScanOptions {
+            max_depth,
+            include_hidden: false,
+        };
+        let found =
+            |max_depth| relative_sorted(&dir, collect_images_recursively(&dir, scan(max_depth)));
+        assert_eq!(found(Some(0)), paths(&["top.jpg"]));
+        assert_eq!(found(Some(1)), paths(&["a/one.png", "top.jpg"]));
+        assert_eq!(
+            found(None),
+            paths(&["a/b/c/three.jpg", "a/b/two.jpg", "a/one.png", "top.jpg"])
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn collect_images_skips_hidden_entries_unless_included() {
+        let dir = temp_dir("hidden");
+        touch(
+            &dir,
+            &[
+                "shown.jpg",
+                ".hidden.jpg",
+                ".thumbnails/thumb.jpg",
+                "sub/inner.png",
+            ],
+        );
+        let scan = |include_hidden| ScanOptions {
+            max_depth: None,
+            include_hidden,
+        };
+        assert_eq!(
+            relative_sorted(&dir, collect_images_recursively(&dir, scan(false))),
+            paths(&["shown.jpg", "sub/inner.png"])
+        );
+        assert_eq!(
+            relative_sorted(&dir, collect_images_recursively(&dir, scan(true))),
+            paths(&[
+                ".hidden.jpg",
+                ".thumbnails/thumb.jpg",
+                "shown.jpg",
+                "sub/inner.png"
+            ])
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+
+    #[test]
+    fn apply_wallpaper_skips_setting_the_image_already_shown() {
+        let setter = MockSetter::default();
+        let state = test_state(AppConfig::default());
+        let path = Path::new("a.jpg");
+
+        let outcome = apply_wallpaper(
+            &setter,
+            &state,
+            path,
+            Some(path),
+            Some(0),
+            false,
+            "sequential",
+        );
+        assert!(outcome.is_ok());
+        assert!(setter.sets.lock().unwrap().is_empty());
+        // 設定を省いても履歴と表示回数は進める
+        assert_eq!(state.history_log.lock().unwrap().len(), 1);
+        assert_eq!(state.show_counts.lock().unwrap().get(path), Some(&1));
+
+        let outcome = apply_wallpaper(
+            &setter,
+            &state,
+            path,
+            Some(Path::new("b.jpg")),
+            Some(0),
+            false,
+            "sequential",
+        );
+        assert!(outcome.is_ok());
+        assert_eq!(*setter.sets.lock().unwrap(), paths(&["a.jpg"]));
+    }
+
+    #[test]
+    fn current_time_slot_switches_on_the_hour() {
+        let hours = TimeSlotHours::default();
+        let slot = |h, min| current_time_slot(hhmm(h, min), &hours);
+        assert_eq!(slot(0, 0), "night");
+        assert_eq!(slot(4, 59), "night");
+        assert_eq!(slot(5, 0), "morning");
+        assert_eq!(slot(11, 59), "morning");
+        assert_eq!(slot(12, 0), "afternoon");
+        assert_eq!(slot(16, 59), "afternoon");
+        assert_eq!(slot(17, 0), "evening");
+        assert_eq!(slot(20, 59), "evening");
+        assert_eq!(slot(21, 0), "night");
+        assert_eq!(slot(23, 59), "night");
+    }
+
+    #[test]
+    fn current_time_slot_uses_custom_hours() {
+        let hours = TimeSlotHours {
+            morning: 7,
+            afternoon: 11,
+            evening: 18,
+            night: 23,
+        };
+        assert_eq!(current_time_slot(hhmm(6, 59), &hours), "night");
+        assert_eq!(current_time_slot(hhmm(7, 0), &hours), "morning");
+        assert_eq!(current_time_slot(hhmm(22, 59), &hours), "evening");
+        assert_eq!(current_time_slot(hhmm(23, 0), &hours), "night");
+    }
+
+    #[test]
+    fn config_errors_checks_location_and_time_slots() {
+        let cfg = AppConfig {
+            latitude: Some(35.0),
+            ..AppConfig::default()
+        };
+        assert_eq!(
+            config_errors(&cfg),
+            ["latitude and longitude must be set together"]
+        );
+
+        let mut cfg = AppConfig::default();
+        cfg.time_slot_hours.evening = cfg.time_slot_hours.afternoon;
+        cfg.time_collections = Some(HashMap::from([("dusk".to_string(), Vec::new())]));
+        let errors = config_errors(&cfg);
+        assert_eq!(errors.len(), 2, "{errors:?}");
+        assert_eq!(errors[0], "unknown time slot: dusk");
+        assert!(errors[1].starts_with("invalid time slot hours"));
+    }
+
+    #[test]
+    fn history_reads_legacy_tuple_entries() {
+        let content = r#"[
+            ["2024-05-01T10:00:00+09:00", "C:\\Pics\\a.jpg"],
+            {"shownAt": "2024-05-01T11:00:00+09:00", "path": "C:\\Pics\\b.jpg", "mode": "random"}
+        ]"#;
+        let entries: Vec<HistoryEntry> = serde_json::from_str::<Vec<StoredHistoryEntry>>(content)
+            .unwrap()
+            .into_iter()
+            .map(HistoryEntry::from)
+            .collect();
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].path, PathBuf::from("C:\\Pics\\a.jpg"));
+        assert_eq!(entries[0].mode, "unknown");
+        assert_eq!(entries[1].mode, "random");
+        assert!(entries[0].shown_at < entries[1].shown_at);
+    }
+
+    #[test]
+    fn dnd_window_takes_precedence_over_schedule() {
+        let cfg = AppConfig {
+            start_dt: Some("08:00".to_string()),
+            end_dt: Some("23:00".to_string()),
+            dnd_start: Some("12:00".to_string()),
+            dnd_end: Some("13:00".to_string()),
+            ..AppConfig::default()
+        };
+        assert!(should_run(local(2024, 5, 1, 11, 59), &cfg, false));
+        assert!(!should_run(local(2024, 5, 1, 12, 30), &cfg, false));
+        assert!(!should_run(local(2024, 5, 1, 12, 30), &cfg, true));
+        assert!(should_run(local(2024, 5, 1, 13, 1), &cfg, true));
+
+        // cron を使っていても DND の判定は変わらない（ループは DND を cron より先に見る）
+        let cron_cfg = AppConfig {
+            cron: Some("0 0 * * * *".to_string()),
+            ..cfg
+        };
+        assert!(in_dnd_window(hhmm(12, 0), &cron_cfg));
+        assert!(!in_dnd_window(hhmm(13, 1), &cron_cfg));
+    }
+
+    #[test]
+    fn dnd_window_wraps_past_midnight() {
+        let cfg = AppConfig {
+            dnd_start: Some("22:00".to_string()),
+            dnd_end: Some("06:00".to_string()),
+            ..AppConfig::default()
+        };
+        assert!(in_dnd_window(hhmm(23, 30), &cfg));
+        assert!(in_dnd_window(hhmm(5, 59), &cfg));
+        assert!(!in_dnd_window(hhmm(12, 0), &cfg));
+    }
+
+    #[test]
+    fn dnd_window_needs_both_ends() {
+        let cfg = AppConfig {
+            dnd_start: Some("22:00".to_string()),
+            ..AppConfig::default()
+        };
+        assert!(!in_dnd_window(hhmm(23, 0), &cfg));
+        assert!(config_errors(&cfg)
+            .iter()
+            .any(|e| e == "dnd_start and dnd_end must be set together"));
+
+        let cfg = AppConfig {
+            dnd_end: Some("06:00".to_string()),
+            ..cfg
+        };
+        assert!(config_errors(&cfg).is_empty());
+    }
+
+    #[test]
+    fn merge_config_form_keeps_edits_but_not_command_managed_fields() {
+        let current = AppConfig {
+            file_targets: paths(&["a.jpg"]),
+            playlists: HashMap::from([("work".to_string(), Playlist::default())]),
+            active_playlist: Some("work".to_string()),
+            hotkey_next: Some("Ctrl+N".to_string()),
+            start_on_login: true,
+            ..AppConfig::default()
+        };
+        // 読み込んだ時点の古い設定に、フォームで変えた項目を重ねて送ってくる
+        let form = serde_json::json!({
+            "interval": 300,
+            "defaultWallpaperPath": "C:\\Pics\\default.jpg",
+            "fileTargets": [],
+            "playlists": {},
+            "activePlaylist": null,
+            "startOnLogin": false,
+        });
+        let merged = merge_config_form(&current, form, false).unwrap();
+        assert_eq!(merged.interval, 300);
+        assert_eq!(
+            merged.default_wallpaper_path,
+            Some(PathBuf::from("C:\\Pics\\default.jpg"))
+        );
+        // 送られなかった項目とコマンドで管理する項目は今の値のまま
+        assert_eq!(merged.hotkey_next.as_deref(), Some("Ctrl+N"));
+        assert_eq!(merged.playlists, current.playlists);
+        assert_eq!(merged.active_playlist.as_deref(), Some("work"));
+        assert!(merged.start_on_login);
+        assert_eq!(merged.file_targets, paths(&["a.jpg"]));
+
+        let form = serde_json::json!({ "fileTargets": [], "defaultWallpaperPath": null });
+        let merged = merge_config_form(&current, form, true).unwrap();
+        assert!(merged.file_targets.is_empty());
+        assert_eq!(merged.default_wallpaper_path, None);
+    }
+
+    #[test]
+    fn playlist_interval_change_reschedules_the_next_change() {
+        let state = test_state(AppConfig {
+            playlists: HashMap::from([(
+                "work".to_string(),
+                Playlist {
+                    targets: paths(&["a.jpg"]),
+                    interval: Some(600),
+                    random: None,
+                },
+            )]),
+            active_playlist: Some("work".to_string()),
+            ..AppConfig::default()
+        });
+        let now = Local::now();
+        let scheduled = now + chrono::Duration::seconds(600);
+        *state.next_change_at.lock().unwrap() = Some(scheduled);
+
+        update_playlist_options(&state, "work".to_string(), Some(30), None).unwrap();
+
+        // ループの 1 周分: まだ切り替える時刻ではないが、新しい間隔で予定を決め直す
+        let reschedule = state.reschedule_requested.swap(false, Ordering::SeqCst);
+        assert!(reschedule);
+        assert!(!interval_change_due(&state, now, false, true));
+        let interval = effective_interval_secs(&state.config.lock().unwrap());
+        let at = next_interval_change(&state, now, interval, 0);
+        *state.next_change_at.lock().unwrap() = Some(at);
+        assert_eq!(at, now + chrono::Duration::seconds(30));
+        assert_ne!(*state.next_change_at.lock().unwrap(), Some(scheduled));
+
+        // 選択中でないプレイリストを変えても予定はそのまま
+        state.config.lock().unwrap().active_playlist = None;
+        update_playlist_options(&state, "work".to_string(), Some(45), None).unwrap();
+        assert!(!state.reschedule_requested.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn prune_prepared_keeps_the_wallpaper_just_set() {
+        let dir = temp_dir("prune_prepared");
+        touch(&dir, &["shown.png", "stale.png", "solid_000000.png"]);
+        prune_prepared(&dir, &dir.join("shown.png"));
+        assert_eq!(
+            relative_sorted(
+                &dir,
+                fs::read_dir(&dir)
+                    .unwrap()
+                    .flatten()
+                    .map(|e| e.path())
+                    .collect()
+            ),
+            paths(&["shown.png"])
+        );
+        let _ = fs::remove_dir_all(&dir);
+    }
+}
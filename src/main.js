@@ -3,6 +3,9 @@ const { open } = window.__TAURI__.dialog;
 const { getCurrentWindow } = window.__TAURI__.window;
 const appWindow = getCurrentWindow();
 
+// 最後に読み込んだ設定。画面に無い項目（ホットキーなど）を保存時に消さないよう引き継ぐ
+let loadedConfig = {};
+
 async function saveConfig() {
   const interval = document.querySelector('input[name="interval"]').value;
   const random = document.querySelector('input[name="random"]').checked;
@@ -20,18 +23,19 @@ async function saveConfig() {
   const monthly = day ? [Number(day)] : null;
 
   const payload = {
+    ...loadedConfig,
     interval: Number(interval),
     random: random,
     startDt: startTime || null,
     endDt: endTime || null,
     weekly: week ? [week] : null,
     monthly: day ? [Number(day)] : null,
-    defaultWallpaperPath: null, // 必要なら設定
-    fileTargets: [] // 必要なら設定
+    fileTargets: [] // 対象は add/remove コマンドで管理するので送らない
   };
 
   //console.log("Saving config:", payload);
-  await invoke("save_config", { config: payload });
+  // clearTargets: false → 空の fileTargets は「未指定」として保存済みの対象を残す
+  await invoke("save_config", { config: payload, clearTargets: false });
 }
 
 function updateEndTimeState() {
@@ -66,6 +70,7 @@ function setupAutoSave() {
 
 async function loadConfig() {
   const cfg = await invoke("load_config_for_frontend");
+  loadedConfig = cfg;
   //console.log("Loaded config:", cfg);
 
   document.querySelector('input[name="interval"]').value = cfg.interval ?? 60;
@@ -126,12 +131,14 @@ document.addEventListener('keydown', async function(e) {
 // ファイル参照
 async function relImage() {
     document.querySelector(".app-btn").addEventListener("click", async () => {
+    // 拡張子は Rust 側で切替対象として扱うものと同じ一覧を使う
+    const extensions = await invoke("get_supported_extensions");
     const selected = await open({
         multiple: true,
         directory: false,
         recursive: true,
         filters: [
-          { name: "Images", extensions: ["jpg", "jpeg", "png", "bmp", "gif", "webp"] }
+          { name: "Images", extensions }
         ]
     });
 